@@ -11,6 +11,7 @@ use crate::sale::VSale;
 
 mod migration_0;
 mod migration_1;
+mod migration_2;
 mod sale;
 mod token_receiver;
 
@@ -29,6 +30,15 @@ pub trait ExtContract {
         deposit_amount: U128,
     ) -> PromiseOrValue<U128>;
 
+    /// Joint callback from checking staked balance of the given user in several staking contracts.
+    fn on_get_account_staked_balances(
+        &mut self,
+        sale_id: u64,
+        token_id: AccountId,
+        sender_id: AccountId,
+        deposit_amount: U128,
+    ) -> PromiseOrValue<U128>;
+
     /// Callback after account creation.
     fn on_create_account(&mut self, new_account_id: AccountId) -> Promise;
 
@@ -47,6 +57,30 @@ pub trait ExtContract {
         sale_id: u64,
     ) -> bool;
 
+    /// Callback after checking the storage registration of the claim recipient
+    fn after_storage_balance_check(
+        &mut self,
+        account_id: AccountId,
+        amount_to_claim: U128,
+        sale_id: u64,
+        storage_deposit_amount: U128,
+    ) -> Promise;
+
+    /// Callback after token distribution by the owner
+    fn after_distribute_purchase(
+        &mut self,
+        account_id: AccountId,
+        amount_to_claim: U128,
+        sale_id: u64,
+    ) -> bool;
+
+    /// Callback after token claim of several sales in one token
+    fn after_withdraw_purchases(
+        &mut self,
+        account_id: AccountId,
+        sale_amounts: Vec<(u64, U128)>,
+    ) -> bool;
+
     /// Callback after token refund for subscription sales
     fn after_refund_purchase(
         &mut self,
@@ -62,6 +96,16 @@ pub trait ExtContract {
         amount: U128,
         sale_id: u64,
     ) -> bool;
+
+    /// Callback after affiliate_rewards claim of several sales in one token
+    fn after_withdraw_affiliate_rewards(
+        &mut self,
+        account_id: AccountId,
+        sale_rewards: Vec<(u64, U128)>,
+    ) -> bool;
+
+    /// Callback after sale dust claim
+    fn after_claim_sale_dust(&mut self, sale_id: u64) -> bool;
 }
 
 #[derive(BorshSerialize, BorshDeserialize)]
@@ -148,6 +192,28 @@ pub(crate) enum StorageKey {
     Affiliates { account_id: AccountId },
     AffiliateLevels { account_id: AccountId, level: u8 },
     AccountsV1,
+    StakingSnapshot { sale_id: u64 },
+    LotteryWinners { sale_id: u64 },
+    DistributionFailures { sale_id: u64 },
+    PayoutFailures { sale_id: u64 },
+    PayoutsInFlight { sale_id: u64 },
+    AccountSaleIds,
+    DepositAttributions { sale_id: u64 },
+    AccountDepositAttributions { sale_id: u64, account_id: AccountId },
+}
+
+/// What happens to the affiliate reward when the referrer chain reaches the owner.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub enum OwnerReferralPolicy {
+    /// Owner accrues the reward.
+    Owner,
+    /// Reward isn't accrued.
+    Drop,
+    /// Given account accrues the reward.
+    Treasury(AccountId),
+    /// Buyer accrues the reward as a bonus.
+    BuyerBonus,
 }
 
 #[near_bindgen]
@@ -162,6 +228,9 @@ struct Contract {
     num_sales: u64,
     // not used anymore
     accounts_old: UnorderedMap<AccountId, AccountOld>,
+    /// Sales the account deposited into.
+    account_sale_ids: LookupMap<AccountId, Vec<u64>>,
+    owner_referral_policy: OwnerReferralPolicy,
 }
 
 #[near_bindgen]
@@ -176,6 +245,8 @@ impl Contract {
             sales: LookupMap::new(StorageKey::Sales),
             num_sales: 0,
             accounts_old: UnorderedMap::new(StorageKey::AccountsV1),
+            account_sale_ids: LookupMap::new(StorageKey::AccountSaleIds),
+            owner_referral_policy: OwnerReferralPolicy::Owner,
         };
         this.accounts.insert(
             &this.owner_id,
@@ -281,6 +352,10 @@ impl Contract {
         self.referral_fees.clone()
     }
 
+    pub fn get_owner_referral_policy(&self) -> OwnerReferralPolicy {
+        self.owner_referral_policy.clone()
+    }
+
     pub fn get_account(&self, account_id: AccountId) -> AccountOutput {
         let account: Account = self
             .accounts
@@ -309,30 +384,24 @@ impl Contract {
 
 #[cfg(test)]
 mod tests {
-    use std::str::FromStr;
-
     use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
     use near_sdk::json_types::U64;
+    use near_sdk::mock::VmAction;
     use near_sdk::test_utils::VMContextBuilder;
-    use near_sdk::test_utils::{accounts, testing_env_with_promise_results};
-    use near_sdk::{serde_json, testing_env, PromiseResult};
+    use near_sdk::test_utils::{accounts, get_created_receipts, testing_env_with_promise_results};
+    use near_sdk::{serde_json, testing_env, CryptoHash, PromiseResult};
 
-    use crate::sale::{SaleInput, SaleMetadata, SaleType};
+    use crate::sale::{
+        get_amount_to_claim, get_purchase_amount, AffiliateRewardToken, BondingCurve, BonusWindow,
+        PayoutKind, SaleFilter, SaleInput, SaleMetadata, SaleType, StorageBalance,
+        StorageBalanceBounds,
+    };
     use crate::token_receiver::SaleDeposit;
 
     use super::*;
 
-    fn contract_with_sale_info(
-        max_amount: Balance,
-        start_date: u64,
-        end_date: u64,
-    ) -> (VMContextBuilder, Contract) {
-        let mut context = VMContextBuilder::new();
-        testing_env!(context.predecessor_account_id(accounts(0)).build());
-        let join_fee = U128(1_000_000);
-        let referral_fees = vec![10, 20, 30];
-        let mut contract = Contract::new(accounts(0), join_fee, referral_fees.clone());
-        contract.create_sale(SaleInput {
+    fn sale_input(max_amount: Option<Balance>, start_date: u64, end_date: u64) -> SaleInput {
+        SaleInput {
             metadata: SaleMetadata {
                 name: "test".to_string(),
                 symbol: "TEST".to_string(),
@@ -350,11 +419,14 @@ mod tests {
             min_near_deposit: U128(100),
             deposit_token_id: accounts(1),
             claim_available: true,
-            distribute_token_id: None,
-            distribute_token_decimals: None,
+            refund_available: false,
+            affiliate_reward_available: false,
+            affiliate_reward_token: None,
+            distribute_token_id: Some(accounts(3)),
+            distribute_token_decimals: Some(24),
             min_buy: U128(100),
             max_buy: U128(10000),
-            max_amount: max_amount.map(|a| U128(a)),
+            max_amount: U128(max_amount.unwrap_or(0)),
             hard_max_amount_limit: max_amount.is_some(),
             start_date: U64(start_date),
             end_date: U64(end_date),
@@ -362,12 +434,34 @@ mod tests {
             whitelist_hash: None,
             limit_per_transaction: U128(100),
             sale_type: SaleType::ByAmount,
-        });
+            floor_price: None,
+            bonding_curve: None,
+            referee_bonus: None,
+            referee_bonus_supply: None,
+            bonus_windows: None,
+        }
+    }
+
+    fn contract_with_sale_input(sale: SaleInput) -> (VMContextBuilder, Contract) {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let join_fee = U128(1_000_000);
+        let referral_fees = vec![10, 20, 30];
+        let mut contract = Contract::new(accounts(0), join_fee, referral_fees.clone());
+        contract.create_sale(sale);
         assert_eq!(contract.get_referral_fees(), referral_fees);
         assert_eq!(contract.get_join_fee(), join_fee);
         (context, contract)
     }
 
+    fn contract_with_sale_info(
+        max_amount: Option<Balance>,
+        start_date: u64,
+        end_date: u64,
+    ) -> (VMContextBuilder, Contract) {
+        contract_with_sale_input(sale_input(max_amount, start_date, end_date))
+    }
+
     fn contract_with_sale() -> (VMContextBuilder, Contract) {
         contract_with_sale_info(Some(10000), 0, 1_000_000_000)
     }
@@ -381,7 +475,7 @@ mod tests {
             .predecessor_account_id(account_id)
             .attached_deposit(1000000)
             .build());
-        contract.join();
+        contract.join(None);
     }
 
     fn deposit(context: &mut VMContextBuilder, contract: &mut Contract, account_id: AccountId) {
@@ -392,6 +486,9 @@ mod tests {
             serde_json::to_string(&SaleDeposit {
                 sale_id: 0,
                 staking_contract: None,
+                staking_contracts: None,
+                staked_amount: None,
+                staking_proof: None,
             })
             .unwrap(),
         );
@@ -408,7 +505,7 @@ mod tests {
             .predecessor_account_id(accounts(2))
             .attached_deposit(1000000)
             .build());
-        contract.join();
+        contract.join(None);
         assert_eq!(contract.get_account(accounts(2)).referrer, accounts(0));
 
         testing_env!(context.predecessor_account_id(accounts(1)).build());
@@ -418,6 +515,9 @@ mod tests {
             serde_json::to_string(&SaleDeposit {
                 sale_id: 0,
                 staking_contract: Some(AccountId::new_unchecked("test.staking".to_string())),
+                staking_contracts: None,
+                staked_amount: None,
+                staking_proof: None,
             })
             .unwrap(),
         );
@@ -427,9 +527,9 @@ mod tests {
                 .current_account_id(accounts(0))
                 .predecessor_account_id(accounts(0))
                 .build(),
-            PromiseResult::Successful(vec![]),
+            PromiseResult::Successful(serde_json::to_vec(&U128(1000)).unwrap()),
         );
-        contract.on_get_account_staked_balance(U128(1000), 0, accounts(1), accounts(2), U128(100));
+        contract.on_get_account_staked_balance(0, accounts(1), accounts(2), U128(100));
 
         assert_eq!(contract.get_sale(0).num_account_sales, 1);
         assert_eq!(contract.get_sale(0).collected_amount.0, 100);
@@ -440,84 +540,1384 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "ERR_NOT_REGISTERED_ACCOUNT")]
-    fn test_not_registered() {
+    fn test_staking_check_failed() {
+        let (mut context, mut contract) = contract_with_sale();
+        register_account(&mut context, &mut contract, accounts(2));
+
+        testing_env_with_promise_results(
+            context
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .build(),
+            PromiseResult::Failed,
+        );
+        let result = contract.on_get_account_staked_balance(0, accounts(1), accounts(2), U128(100));
+        match result {
+            PromiseOrValue::Value(return_amount) => assert_eq!(return_amount.0, 100),
+            _ => panic!("ERR_EXPECTED_VALUE"),
+        }
+        assert_eq!(contract.get_sale(0).num_account_sales, 0);
+        assert_eq!(contract.get_sale(0).collected_amount.0, 0);
+    }
+
+    #[test]
+    fn test_staking_check_malformed() {
         let (mut context, mut contract) = contract_with_sale();
+        register_account(&mut context, &mut contract, accounts(2));
+
+        testing_env_with_promise_results(
+            context
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .build(),
+            PromiseResult::Successful(b"\"abc\"".to_vec()),
+        );
+        let result = contract.on_get_account_staked_balance(0, accounts(1), accounts(2), U128(100));
+        match result {
+            PromiseOrValue::Value(return_amount) => assert_eq!(return_amount.0, 100),
+            _ => panic!("ERR_EXPECTED_VALUE"),
+        }
+        assert_eq!(contract.get_sale(0).num_account_sales, 0);
+        assert_eq!(contract.get_sale(0).collected_amount.0, 0);
+    }
+
+    fn staking_pool(index: usize) -> AccountId {
+        AccountId::new_unchecked(format!("pool{}.staking", index))
+    }
+
+    fn contract_with_staking_pools() -> (VMContextBuilder, Contract) {
+        contract_with_sale_input(SaleInput {
+            staking_contracts: vec![staking_pool(0), staking_pool(1)],
+            ..sale_input(Some(10000), 0, 1_000_000_000)
+        })
+    }
+
+    fn deposit_with_staking_pools(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        account_id: AccountId,
+        staking_contracts: Vec<AccountId>,
+    ) {
         testing_env!(context.predecessor_account_id(accounts(1)).build());
         contract.ft_on_transfer(
-            accounts(2),
+            account_id,
             U128(100),
             serde_json::to_string(&SaleDeposit {
                 sale_id: 0,
                 staking_contract: None,
+                staking_contracts: Some(staking_contracts),
+                staked_amount: None,
+                staking_proof: None,
             })
             .unwrap(),
         );
     }
 
     #[test]
-    #[should_panic(expected = "ERR_NO_SALE")]
-    fn test_no_sale() {
-        let (mut context, mut contract) = contract_with_sale();
+    #[should_panic(expected = "ERR_TOO_MANY_STAKING_CONTRACTS")]
+    fn test_create_sale_too_many_staking_contracts() {
+        contract_with_sale_input(SaleInput {
+            staking_contracts: (0..5).map(staking_pool).collect(),
+            ..sale_input(Some(10000), 0, 1_000_000_000)
+        });
+    }
+
+    #[test]
+    fn test_staked_balances_summed() {
+        let (mut context, mut contract) = contract_with_staking_pools();
+        register_account(&mut context, &mut contract, accounts(2));
+
+        // Empty list queries every staking contract of the sale.
+        deposit_with_staking_pools(&mut context, &mut contract, accounts(2), vec![]);
+        let staking_calls = get_created_receipts()
+            .iter()
+            .filter(|receipt| {
+                matches!(
+                    &receipt.actions[0],
+                    VmAction::FunctionCall { method_name, .. }
+                        if method_name == "get_account_staked_balance"
+                )
+            })
+            .count();
+        assert_eq!(staking_calls, 2);
+
+        // Neither pool alone reaches min_near_deposit of 100, together they do.
+        testing_env!(
+            context
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![
+                PromiseResult::Successful(serde_json::to_vec(&U128(60)).unwrap()),
+                PromiseResult::Successful(serde_json::to_vec(&U128(60)).unwrap()),
+            ]
+        );
+        contract.on_get_account_staked_balances(0, accounts(1), accounts(2), U128(100));
+        assert_eq!(contract.get_sale(0).collected_amount.0, 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DUPLICATE_STAKING_CONTRACT")]
+    fn test_duplicate_staking_contracts() {
+        let (mut context, mut contract) = contract_with_staking_pools();
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit_with_staking_pools(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            vec![staking_pool(0), staking_pool(0)],
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_WHITELISTED_STAKING_CONTRACT")]
+    fn test_not_whitelisted_staking_contract() {
+        let (mut context, mut contract) = contract_with_staking_pools();
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit_with_staking_pools(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            vec![staking_pool(0), staking_pool(2)],
+        );
+    }
+
+    fn snapshot_leaf(account_id: AccountId, staked_amount: Balance) -> CryptoHash {
+        let mut data = account_id.as_str().as_bytes().to_vec();
+        data.extend_from_slice(&staked_amount.to_le_bytes());
+        let mut leaf = CryptoHash::default();
+        leaf.copy_from_slice(&env::sha256(&data));
+        leaf
+    }
+
+    fn snapshot_node(a: CryptoHash, b: CryptoHash) -> CryptoHash {
+        let (left, right) = if a <= b { (a, b) } else { (b, a) };
+        let mut node = CryptoHash::default();
+        node.copy_from_slice(&env::sha256(&[left, right].concat()));
+        node
+    }
+
+    /// Registers the root before the sale starts and deposits 100 for accounts(2) with the proof.
+    fn deposit_with_staking_proof(
+        root: CryptoHash,
+        staked_amount: Balance,
+        staking_proof: Option<Vec<CryptoHash>>,
+    ) -> Contract {
+        let (mut context, mut contract) =
+            contract_with_sale_info(Some(10000), 1_000, 1_000_000_000);
+        testing_env!(context.current_account_id(accounts(0)).build());
+        contract.update_sale_staking_snapshot_root(0, root);
+        register_account(&mut context, &mut contract, accounts(2));
         testing_env!(context
-            .predecessor_account_id(accounts(2))
-            .attached_deposit(1000000)
+            .block_timestamp(1_000)
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(0)
             .build());
-        contract.join();
-        testing_env!(context.predecessor_account_id(accounts(1)).build());
         contract.ft_on_transfer(
             accounts(2),
             U128(100),
             serde_json::to_string(&SaleDeposit {
-                sale_id: 1,
+                sale_id: 0,
                 staking_contract: None,
+                staking_contracts: None,
+                staked_amount: Some(U128(staked_amount)),
+                staking_proof,
             })
             .unwrap(),
         );
+        contract
+    }
+
+    /// Snapshot tree of accounts(2) => 500, accounts(4) => 300, accounts(5) => 200.
+    fn snapshot_tree() -> (CryptoHash, Vec<CryptoHash>) {
+        let sibling = snapshot_leaf(accounts(4), 300);
+        let uncle = snapshot_leaf(accounts(5), 200);
+        let root = snapshot_node(
+            snapshot_node(snapshot_leaf(accounts(2), 500), sibling),
+            uncle,
+        );
+        (root, vec![sibling, uncle])
     }
 
     #[test]
-    fn test_create_remove_link() {
+    fn test_staking_proof() {
+        let (root, proof) = snapshot_tree();
+        let contract = deposit_with_staking_proof(root, 500, Some(proof));
+        assert_eq!(contract.get_sale(0).collected_amount.0, 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_STAKING_PROOF")]
+    fn test_staking_proof_bad_sibling() {
+        let (root, mut proof) = snapshot_tree();
+        proof[0][0] ^= 1;
+        deposit_with_staking_proof(root, 500, Some(proof));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_STAKING_PROOF")]
+    fn test_staking_proof_wrong_leaf() {
+        let (root, proof) = snapshot_tree();
+        deposit_with_staking_proof(root, 600, Some(proof));
+    }
+
+    #[test]
+    fn test_staking_proof_single_leaf() {
+        // Tree of a single account has the leaf as its root and an empty proof.
+        let contract = deposit_with_staking_proof(snapshot_leaf(accounts(2), 500), 500, None);
+        assert_eq!(contract.get_sale(0).collected_amount.0, 100);
+    }
+
+    /// Deposits 100 for the account and moves past the sale end.
+    fn contract_with_ended_sale(account_id: AccountId) -> (VMContextBuilder, Contract) {
         let (mut context, mut contract) = contract_with_sale();
+        register_account(&mut context, &mut contract, account_id.clone());
+        deposit_to_sale(&mut context, &mut contract, 0, account_id, 100);
+        testing_env!(context.block_timestamp(1_000_000_001).build());
+        (context, contract)
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_PAYOUT_IN_PROGRESS")]
+    fn test_claim_while_in_flight() {
+        let (mut context, mut contract) = contract_with_ended_sale(accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_purchase(0, None);
+        contract.claim_purchase(0, None);
+    }
+
+    #[test]
+    fn test_claim_after_failed_transfer() {
+        let (mut context, mut contract) = contract_with_ended_sale(accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_purchase(0, None);
+        let amount_to_claim = contract.get_sale_account(0, accounts(2)).claimed;
+        assert_ne!(amount_to_claim.0, 0);
+
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Failed,
+        );
+        assert!(!contract.after_withdraw_purchase(accounts(2), amount_to_claim, 0));
+        assert_eq!(contract.get_sale_account(0, accounts(2)).claimed.0, 0);
+        assert_eq!(contract.get_sale_payout_failures(0, 0, 10).len(), 1);
+
+        // Claim again once the failed transfer is resolved, then its callback succeeds.
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_purchase(0, None);
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Successful(vec![]),
+        );
+        assert!(contract.after_withdraw_purchase(accounts(2), amount_to_claim, 0));
+        assert_eq!(
+            contract.get_sale_account(0, accounts(2)).claimed.0,
+            amount_to_claim.0
+        );
+        assert_eq!(contract.get_sale_payout_failures(0, 0, 10).len(), 1);
+    }
+
+    /// Oversubscribed sale where the account deposited 200 for the allocation of 100.
+    fn contract_with_ended_subscription(account_id: AccountId) -> (VMContextBuilder, Contract) {
+        let (mut context, mut contract) = contract_with_sale_input(SaleInput {
+            refund_available: true,
+            hard_max_amount_limit: false,
+            limit_per_transaction: U128(200),
+            sale_type: SaleType::BySubscription,
+            ..sale_input(Some(100), 0, 1_000_000_000)
+        });
+        register_account(&mut context, &mut contract, account_id.clone());
+        deposit_to_sale(&mut context, &mut contract, 0, account_id, 200);
+        testing_env!(context.block_timestamp(1_000_000_001).build());
+        (context, contract)
+    }
+
+    fn deposit_to_sale(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        sale_id: u64,
+        account_id: AccountId,
+        amount: Balance,
+    ) {
+        testing_env_with_promise_results(
+            context
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .attached_deposit(0)
+                .build(),
+            PromiseResult::Successful(serde_json::to_vec(&U128(1000)).unwrap()),
+        );
+        contract.on_get_account_staked_balance(sale_id, accounts(1), account_id, U128(amount));
+    }
+
+    fn join_with_referrer(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        account_id: AccountId,
+        referrer_id: AccountId,
+    ) {
         testing_env!(context
-            .predecessor_account_id(accounts(2))
+            .predecessor_account_id(account_id)
             .attached_deposit(1000000)
             .build());
-        contract.join();
+        contract.join(Some(referrer_id));
+    }
+
+    fn other_token() -> AccountId {
+        AccountId::new_unchecked("other.token".to_string())
+    }
+
+    /// Three ended sales where the account referred by accounts(4) deposited 10000 into each.
+    /// The last sale distributes and pays affiliate rewards in another token.
+    fn contract_with_ended_sales(account_id: AccountId) -> (VMContextBuilder, Contract) {
+        let sale = || SaleInput {
+            limit_per_transaction: U128(10000),
+            affiliate_reward_available: true,
+            ..sale_input(Some(100000), 0, 1_000_000_000)
+        };
+        let (mut context, mut contract) = contract_with_sale_input(sale());
+        contract.create_sale(sale());
+        contract.create_sale(SaleInput {
+            distribute_token_id: Some(other_token()),
+            affiliate_reward_token: Some(AffiliateRewardToken::DistributeToken),
+            ..sale()
+        });
+        register_account(&mut context, &mut contract, accounts(4));
+        join_with_referrer(&mut context, &mut contract, account_id.clone(), accounts(4));
+        for sale_id in 0..3 {
+            deposit_to_sale(
+                &mut context,
+                &mut contract,
+                sale_id,
+                account_id.clone(),
+                10000,
+            );
+        }
+        testing_env!(context.block_timestamp(1_000_000_001).build());
+        (context, contract)
+    }
+
+    /// Token and amount of every ft_transfer sent by the last call.
+    fn get_ft_transfers() -> Vec<(AccountId, U128)> {
+        get_created_receipts()
+            .into_iter()
+            .filter_map(|receipt| match &receipt.actions[0] {
+                VmAction::FunctionCall {
+                    method_name, args, ..
+                } if method_name == "ft_transfer" => {
+                    let args: serde_json::Value = serde_json::from_slice(args).unwrap();
+                    Some((
+                        receipt.receiver_id.clone(),
+                        serde_json::from_value(args["amount"].clone()).unwrap(),
+                    ))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Oversubscribed sale of 100 at price 7 where three accounts deposited 100 each.
+    fn contract_with_finalized_subscription() -> (VMContextBuilder, Contract) {
+        let (mut context, mut contract) = contract_with_sale_input(SaleInput {
+            hard_max_amount_limit: false,
+            price: U128(7),
+            sale_type: SaleType::BySubscription,
+            ..sale_input(Some(100), 0, 1_000_000_000)
+        });
+        for account_id in [accounts(2), accounts(4), accounts(5)] {
+            register_account(&mut context, &mut contract, account_id.clone());
+            deposit_to_sale(&mut context, &mut contract, 0, account_id, 100);
+        }
         testing_env!(context
-            .predecessor_account_id(accounts(2))
-            .attached_deposit(CREATE_LINK_AMOUNT)
+            .block_timestamp(1_000_000_001)
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
             .build());
-        let pk = PublicKey::from_str("qSq3LoufLvTCTNGC3LJePMDGrok8dHMQ5A1YD9psbiz").unwrap();
-        contract.create_link(pk.clone());
-        contract.remove_link(pk);
+        contract.finalize_sale(0, 0, 10);
+        (context, contract)
     }
 
     #[test]
-    #[should_panic = "ERR_SALE_NOT_STARTED"]
-    fn test_sale_too_early() {
-        let (mut context, mut contract) = contract_with_sale_info(None, 1_000, 1_000_000);
-        register_account(&mut context, &mut contract, accounts(2));
-        deposit(&mut context, &mut contract, accounts(2));
+    #[should_panic(expected = "ERR_SALE_IN_PROGRESS")]
+    fn test_finalize_sale_before_end() {
+        let (mut context, mut contract) = contract_with_sale();
+        testing_env!(context.current_account_id(accounts(0)).build());
+        contract.finalize_sale(0, 0, 10);
     }
 
     #[test]
-    #[should_panic = "ERR_SALE_DONE"]
-    fn test_sale_too_late() {
-        let (mut context, mut contract) = contract_with_sale_info(None, 1_000, 1_000_000);
-        register_account(&mut context, &mut contract, accounts(2));
-        testing_env!(context
-            .block_timestamp(1_000_001)
-            .predecessor_account_id(accounts(1))
-            .build());
-        contract.ft_on_transfer(
-            accounts(2),
-            U128(100),
-            serde_json::to_string(&SaleDeposit {
-                sale_id: 0,
-                staking_contract: None,
+    #[should_panic(expected = "ERR_SALE_FINALIZED")]
+    fn test_finalize_sale_twice() {
+        let (_, mut contract) = contract_with_finalized_subscription();
+        contract.finalize_sale(0, 0, 10);
+    }
+
+    #[test]
+    fn test_claim_sale_dust() {
+        let (_, mut contract) = contract_with_finalized_subscription();
+        let claims: Balance = [accounts(2), accounts(4), accounts(5)]
+            .iter()
+            .map(|account_id| {
+                contract
+                    .get_sale_account(0, account_id.clone())
+                    .amount_to_claim
+                    .0
             })
-            .unwrap(),
+            .sum();
+        let dust_amount = get_purchase_amount(100, 7, 24) - claims;
+        assert_ne!(dust_amount, 0);
+        assert_eq!(contract.get_sale(0).dust_amount.0, dust_amount);
+
+        contract.claim_sale_dust(0);
+        assert_eq!(get_ft_transfers(), vec![(accounts(3), U128(dust_amount))]);
+        assert!(contract.get_sale(0).dust_claimed);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ALREADY_CLAIMED")]
+    fn test_claim_sale_dust_twice() {
+        let (_, mut contract) = contract_with_finalized_subscription();
+        contract.claim_sale_dust(0);
+        contract.claim_sale_dust(0);
+    }
+
+    /// Sale 0 is created under the default policy, sale 1 under the given one.
+    fn contract_with_referral_policy(policy: OwnerReferralPolicy) -> (VMContextBuilder, Contract) {
+        let sale = || SaleInput {
+            limit_per_transaction: U128(10000),
+            ..sale_input(Some(100000), 0, 1_000_000_000)
+        };
+        let (mut context, mut contract) = contract_with_sale_input(sale());
+        testing_env!(context.current_account_id(accounts(0)).build());
+        contract.update_owner_referral_policy(policy);
+        contract.create_sale(sale());
+        (context, contract)
+    }
+
+    /// Deposit of an account referred by the owner into sales 0 and 1.
+    fn assert_owner_referral_policy(policy: OwnerReferralPolicy, receiver_id: Option<AccountId>) {
+        let (mut context, mut contract) = contract_with_referral_policy(policy);
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit_to_sale(&mut context, &mut contract, 0, accounts(2), 10000);
+        deposit_to_sale(&mut context, &mut contract, 1, accounts(2), 10000);
+
+        // Policy applies once to the level 1 reward, the owner doesn't refer itself further.
+        let accrued_amount = if let Some(receiver_id) = receiver_id {
+            assert_eq!(contract.get_affiliate_account(1, receiver_id).amount.0, 10);
+            10
+        } else {
+            0
+        };
+        assert_eq!(
+            contract.get_sale_stats(1).affiliate_rewards_accrued.0,
+            accrued_amount
         );
+        assert!(contract.get_affiliate_leaderboard(1).is_empty());
+
+        // Sale created before the update keeps its policy.
+        assert_eq!(contract.get_affiliate_account(0, accounts(0)).amount.0, 10);
+    }
+
+    #[test]
+    fn test_owner_referral_policy_owner() {
+        assert_owner_referral_policy(OwnerReferralPolicy::Owner, Some(accounts(0)));
+    }
+
+    #[test]
+    fn test_owner_referral_policy_drop() {
+        assert_owner_referral_policy(OwnerReferralPolicy::Drop, None);
+    }
+
+    #[test]
+    fn test_owner_referral_policy_treasury() {
+        assert_owner_referral_policy(
+            OwnerReferralPolicy::Treasury(accounts(5)),
+            Some(accounts(5)),
+        );
+    }
+
+    #[test]
+    fn test_owner_referral_policy_buyer_bonus() {
+        assert_owner_referral_policy(OwnerReferralPolicy::BuyerBonus, Some(accounts(2)));
+    }
+
+    #[test]
+    fn test_owner_referral_policy_chain() {
+        let (mut context, mut contract) =
+            contract_with_referral_policy(OwnerReferralPolicy::Treasury(accounts(5)));
+        register_account(&mut context, &mut contract, accounts(2));
+        join_with_referrer(&mut context, &mut contract, accounts(4), accounts(2));
+        deposit_to_sale(&mut context, &mut contract, 1, accounts(4), 10000);
+
+        // Level 2 reaches the owner, so the treasury gets it and level 3 isn't accrued.
+        assert_eq!(contract.get_affiliate_account(1, accounts(2)).amount.0, 10);
+        assert_eq!(contract.get_affiliate_account(1, accounts(5)).amount.0, 20);
+        assert_eq!(contract.get_sale_stats(1).affiliate_rewards_accrued.0, 30);
+        assert_eq!(
+            contract.get_affiliate_leaderboard(1),
+            vec![(accounts(2), U128(10))]
+        );
+    }
+
+    /// Referral tree of accounts(2) => [accounts(3) => [accounts(5)], accounts(4)].
+    fn contract_with_referral_tree() -> Contract {
+        let (mut context, mut contract) = contract_with_sale();
+        register_account(&mut context, &mut contract, accounts(2));
+        join_with_referrer(&mut context, &mut contract, accounts(3), accounts(2));
+        join_with_referrer(&mut context, &mut contract, accounts(4), accounts(2));
+        join_with_referrer(&mut context, &mut contract, accounts(5), accounts(3));
+        contract
+    }
+
+    fn get_subtree_page(
+        contract: &Contract,
+        cursor: Option<Vec<u64>>,
+        limit: u64,
+    ) -> (Vec<(AccountId, u64)>, Option<Vec<u64>>) {
+        let (nodes, cursor) = contract.get_referral_subtree(accounts(2), cursor, limit);
+        (
+            nodes
+                .into_iter()
+                .map(|node| (node.account_id, node.depth))
+                .collect(),
+            cursor,
+        )
+    }
+
+    #[test]
+    fn test_referral_subtree_pages() {
+        let contract = contract_with_referral_tree();
+
+        // Page ends deeper than it started, next one goes back up.
+        let (nodes, cursor) = get_subtree_page(&contract, None, 2);
+        assert_eq!(nodes, vec![(accounts(2), 0), (accounts(3), 1)]);
+        assert_eq!(cursor, Some(vec![0, 0]));
+        let (nodes, cursor) = get_subtree_page(&contract, cursor, 2);
+        assert_eq!(nodes, vec![(accounts(5), 2), (accounts(4), 1)]);
+        assert_eq!(cursor, None);
+
+        // Page ends at the deepest account, next one continues with its uncle.
+        let (nodes, cursor) = get_subtree_page(&contract, None, 3);
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(cursor, Some(vec![1]));
+        let (nodes, cursor) = get_subtree_page(&contract, cursor, 3);
+        assert_eq!(nodes, vec![(accounts(4), 1)]);
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_LIMIT")]
+    fn test_referral_subtree_zero_limit() {
+        let contract = contract_with_referral_tree();
+        contract.get_referral_subtree(accounts(2), None, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_LIMIT")]
+    fn test_referral_subtree_limit_too_big() {
+        let contract = contract_with_referral_tree();
+        contract.get_referral_subtree(accounts(2), None, 101);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOO_MANY_SALES")]
+    fn test_claim_purchases_too_many_sales() {
+        let (mut context, mut contract) = contract_with_ended_sales(accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_purchases((0..11).collect());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_GAS")]
+    fn test_claim_purchases_not_enough_gas() {
+        let (mut context, mut contract) = contract_with_ended_sales(accounts(2));
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .prepaid_gas(Gas(50_000_000_000_000))
+            .build());
+        contract.claim_purchases(vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_sale_summaries() {
+        let (mut context, mut contract) = contract_with_sale();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.create_sale(sale_input(Some(10000), 2_000, 3_000));
+        contract.create_sale(sale_input(Some(10000), 0, 10));
+        testing_env!(context.block_timestamp(100).build());
+
+        let sale_ids = |from_index, limit, filter| -> Vec<u64> {
+            contract
+                .get_sale_summaries(from_index, limit, filter)
+                .iter()
+                .map(|summary| summary.sale_id)
+                .collect()
+        };
+        assert_eq!(sale_ids(0, 10, None), vec![0, 1, 2]);
+        assert_eq!(sale_ids(1, 1, None), vec![1]);
+        assert_eq!(sale_ids(0, 10, Some(SaleFilter::Active)), vec![0]);
+        assert_eq!(sale_ids(0, 10, Some(SaleFilter::Upcoming)), vec![1]);
+        assert_eq!(sale_ids(0, 10, Some(SaleFilter::EndedClaimable)), vec![2]);
+        // Filter applies within the page.
+        assert!(sale_ids(0, 2, Some(SaleFilter::EndedClaimable)).is_empty());
+    }
+
+    #[test]
+    fn test_sale_stats() {
+        let (mut context, mut contract) = contract_with_ended_subscription(accounts(2));
+        let stats = contract.get_sale_stats(0);
+        assert_eq!(stats.oversubscription_ratio, 20000);
+        assert_eq!(stats.staked_deposit_amount.0, 200);
+        assert_eq!(stats.non_staked_deposit_amount.0, 0);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_purchase(0, None);
+        let amount_to_claim = get_purchase_amount(100, 1000, 24);
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Successful(vec![]),
+        );
+        contract.after_withdraw_purchase(accounts(2), U128(amount_to_claim), 0);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_refund(0);
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Successful(vec![]),
+        );
+        contract.after_refund_purchase(accounts(2), U128(100), 0);
+
+        let stats = contract.get_sale_stats(0);
+        assert_eq!(stats.claimed_amount.0, amount_to_claim);
+        assert_eq!(stats.num_claimers, 1);
+        assert_eq!(stats.refunded_amount.0, 100);
+    }
+
+    #[test]
+    fn test_affiliate_leaderboard_eviction() {
+        let (mut context, mut contract) = contract_with_sale_input(SaleInput {
+            max_buy: U128(100000),
+            limit_per_transaction: U128(100000),
+            ..sale_input(Some(10_000_000), 0, 1_000_000_000)
+        });
+        let referrer = |index| AccountId::new_unchecked(format!("referrer{}.near", index));
+        let buyer = |index| AccountId::new_unchecked(format!("buyer{}.near", index));
+        // Level 1 fee is 0.1%, so referrer i accrues i + 1.
+        for index in 0..11 {
+            register_account(&mut context, &mut contract, referrer(index));
+            join_with_referrer(&mut context, &mut contract, buyer(index), referrer(index));
+            deposit_to_sale(
+                &mut context,
+                &mut contract,
+                0,
+                buyer(index),
+                (index as u128 + 1) * 1000,
+            );
+        }
+        let leaderboard = contract.get_affiliate_leaderboard(0);
+        assert_eq!(leaderboard.len(), 10);
+        assert_eq!(leaderboard[0], (referrer(10), U128(11)));
+        assert_eq!(leaderboard[9], (referrer(1), U128(2)));
+
+        // Evicted affiliate comes back on top once its reward grows, pushing out the last one.
+        deposit_to_sale(&mut context, &mut contract, 0, buyer(0), 20000);
+        let leaderboard = contract.get_affiliate_leaderboard(0);
+        assert_eq!(leaderboard.len(), 10);
+        assert_eq!(leaderboard[0], (referrer(0), U128(21)));
+        assert_eq!(leaderboard[9], (referrer(2), U128(3)));
+    }
+
+    #[test]
+    fn test_deposit_attributions() {
+        let (mut context, mut contract) = contract_with_sale_input(SaleInput {
+            limit_per_transaction: U128(10000),
+            max_buy: U128(20000),
+            ..sale_input(Some(100000), 0, 1_000_000_000)
+        });
+        register_account(&mut context, &mut contract, accounts(2));
+        join_with_referrer(&mut context, &mut contract, accounts(4), accounts(2));
+        testing_env!(context.block_timestamp(10).build());
+        deposit_to_sale(&mut context, &mut contract, 0, accounts(4), 10000);
+        testing_env!(context.block_timestamp(20).build());
+        deposit_to_sale(&mut context, &mut contract, 0, accounts(4), 5000);
+
+        // Every deposit keeps the rewards it accrued, the chain ends at the owner on level 2.
+        let attributions = contract.get_deposit_attributions(0, accounts(4), 0, 10);
+        assert_eq!(attributions.len(), 2);
+        assert_eq!(attributions[0].amount.0, 10000);
+        assert_eq!(attributions[0].timestamp.0, 10);
+        assert_eq!(
+            attributions[0].affiliate_rewards,
+            vec![(accounts(2), U128(10)), (accounts(0), U128(20))]
+        );
+        assert_eq!(attributions[1].amount.0, 5000);
+        assert_eq!(attributions[1].timestamp.0, 20);
+        assert_eq!(
+            attributions[1].affiliate_rewards,
+            vec![(accounts(2), U128(5)), (accounts(0), U128(10))]
+        );
+        assert_eq!(
+            contract
+                .get_deposit_attributions(0, accounts(4), 1, 10)
+                .len(),
+            1
+        );
+        assert_eq!(
+            contract.get_sale_deposit_attributions(0, 0, 10),
+            vec![(accounts(4), 2)]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOO_MANY_DEPOSITS")]
+    fn test_too_many_deposits() {
+        let (mut context, mut contract) = contract_with_sale_input(SaleInput {
+            max_buy: U128(20000),
+            ..sale_input(Some(100000), 0, 1_000_000_000)
+        });
+        register_account(&mut context, &mut contract, accounts(2));
+        for _ in 0..101 {
+            deposit_to_sale(&mut context, &mut contract, 0, accounts(2), 100);
+        }
+    }
+
+    #[test]
+    fn test_get_account_sales_pages() {
+        let (_, contract) = contract_with_ended_sales(accounts(2));
+        let sale_ids = |from_index, limit| -> Vec<u64> {
+            contract
+                .get_account_sales(accounts(2), from_index, limit)
+                .iter()
+                .map(|account_sale| account_sale.sale_id)
+                .collect()
+        };
+        assert_eq!(sale_ids(0, 2), vec![0, 1]);
+        assert_eq!(sale_ids(2, 2), vec![2]);
+        assert!(sale_ids(3, 2).is_empty());
+    }
+
+    #[test]
+    fn test_claim_purchases_by_token() {
+        let (mut context, mut contract) = contract_with_ended_sales(accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_purchases(vec![0, 1, 2]);
+        let amount = get_purchase_amount(10000, 1000, 24);
+        assert_eq!(
+            get_ft_transfers(),
+            vec![
+                (accounts(3), U128(2 * amount)),
+                (other_token(), U128(amount))
+            ]
+        );
+
+        // Only the sales of the failed transfer can be claimed again.
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Failed,
+        );
+        assert!(!contract.after_withdraw_purchases(accounts(2), vec![(2, U128(amount))]));
+        testing_env_with_promise_results(context.build(), PromiseResult::Successful(vec![]));
+        assert!(contract
+            .after_withdraw_purchases(accounts(2), vec![(0, U128(amount)), (1, U128(amount))]));
+        assert_eq!(contract.get_sale_account(0, accounts(2)).claimed.0, amount);
+        assert_eq!(contract.get_sale_account(1, accounts(2)).claimed.0, amount);
+        assert_eq!(contract.get_sale_account(2, accounts(2)).claimed.0, 0);
+        assert_eq!(contract.get_sale_payout_failures(2, 0, 10).len(), 1);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_purchases(vec![0, 1, 2]);
+        assert_eq!(get_ft_transfers(), vec![(other_token(), U128(amount))]);
+    }
+
+    #[test]
+    fn test_claim_all_affiliate_rewards_by_token() {
+        let (mut context, mut contract) = contract_with_ended_sales(accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.claim_all_affiliate_rewards(vec![0, 1, 2]);
+        // 0.1% of the deposit, converted at the sale price in the last sale.
+        let reward = 10;
+        let distribute_token_reward = get_purchase_amount(reward, 1000, 24);
+        assert_eq!(
+            get_ft_transfers(),
+            vec![
+                (accounts(1), U128(2 * reward)),
+                (other_token(), U128(distribute_token_reward))
+            ]
+        );
+
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Failed,
+        );
+        assert!(!contract.after_withdraw_affiliate_rewards(
+            accounts(4),
+            vec![(0, U128(reward)), (1, U128(reward))]
+        ));
+        testing_env_with_promise_results(context.build(), PromiseResult::Successful(vec![]));
+        assert!(contract.after_withdraw_affiliate_rewards(
+            accounts(4),
+            vec![(2, U128(distribute_token_reward))]
+        ));
+        assert_eq!(contract.get_affiliate_account(0, accounts(4)).claimed.0, 0);
+        assert_eq!(contract.get_affiliate_account(1, accounts(4)).claimed.0, 0);
+        assert_eq!(
+            contract.get_affiliate_account(2, accounts(4)).claimed.0,
+            distribute_token_reward
+        );
+    }
+
+    #[test]
+    fn test_distribute_to_unregistered_account() {
+        let (mut context, mut contract) = contract_with_ended_sale(accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        assert_eq!(contract.distribute_sale(0, 0, 10), 1);
+        let amount_to_claim = contract.get_sale_account(0, accounts(2)).claimed;
+
+        // Receiver isn't registered with the token.
+        testing_env_with_promise_results(context.build(), PromiseResult::Failed);
+        assert!(!contract.after_distribute_purchase(accounts(2), amount_to_claim, 0));
+        assert_eq!(
+            contract.get_sale_distribution_failures(0, 0, 10),
+            vec![(accounts(2), amount_to_claim)]
+        );
+
+        // Purchase stays claimable by the account itself.
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_purchase(0, None);
+        assert_eq!(
+            contract.get_sale_account(0, accounts(2)).claimed,
+            amount_to_claim
+        );
+        assert!(contract.get_sale_distribution_failures(0, 0, 10).is_empty());
+    }
+
+    #[test]
+    fn test_referee_bonus_supply_cap() {
+        let amount = get_purchase_amount(10000, 1000, 24);
+        let (mut context, mut contract) = contract_with_sale_input(SaleInput {
+            limit_per_transaction: U128(10000),
+            referee_bonus: Some(1000),
+            referee_bonus_supply: Some(U128(amount * 3 / 20)),
+            ..sale_input(Some(100000), 0, 1_000_000_000)
+        });
+        register_account(&mut context, &mut contract, accounts(4));
+        for account_id in [accounts(2), accounts(5)] {
+            join_with_referrer(&mut context, &mut contract, account_id.clone(), accounts(4));
+            deposit_to_sale(&mut context, &mut contract, 0, account_id, 10000);
+        }
+        testing_env!(context.block_timestamp(1_000_000_001).build());
+        for account_id in [accounts(2), accounts(5)] {
+            testing_env!(context.predecessor_account_id(account_id).build());
+            contract.claim_purchase(0, None);
+        }
+
+        // 10% bonus for the first referee, the rest of the supply for the second one.
+        assert_eq!(
+            contract.get_sale_account(0, accounts(2)).referee_bonus.0,
+            amount / 10
+        );
+        let account_sale = contract.get_sale_account(0, accounts(5));
+        assert_eq!(account_sale.referee_bonus.0, amount / 20);
+        assert_eq!(account_sale.claimed.0, amount + amount / 20);
+        assert_eq!(
+            contract.get_sale(0).referee_bonus_allocated.0,
+            amount * 3 / 20
+        );
+    }
+
+    #[test]
+    fn test_bonus_window_amounts() {
+        let (mut context, mut contract) = contract_with_sale_input(SaleInput {
+            max_buy: U128(20000),
+            limit_per_transaction: U128(10000),
+            affiliate_reward_available: true,
+            affiliate_reward_token: Some(AffiliateRewardToken::DistributeToken),
+            bonus_windows: Some(vec![BonusWindow {
+                end_date: U64(100),
+                bonus: 1000,
+            }]),
+            ..sale_input(Some(100000), 0, 1_000_000_000)
+        });
+        register_account(&mut context, &mut contract, accounts(4));
+        join_with_referrer(&mut context, &mut contract, accounts(2), accounts(4));
+        testing_env!(context.block_timestamp(50).build());
+        deposit_to_sale(&mut context, &mut contract, 0, accounts(2), 10000);
+        testing_env!(context.block_timestamp(200).build());
+        deposit_to_sale(&mut context, &mut contract, 0, accounts(2), 10000);
+
+        // Only the deposit made during the window counts for the bonus.
+        assert_eq!(contract.get_sale(0).bonus_window_amounts, vec![U128(10000)]);
+        assert_eq!(
+            contract.get_sale_account(0, accounts(2)).window_amounts,
+            vec![U128(10000)]
+        );
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(1_000_000_001)
+            .build());
+        contract.claim_purchase(0, None);
+        assert_eq!(
+            contract.get_sale_account(0, accounts(2)).claimed.0,
+            get_purchase_amount(20000, 1000, 24) + get_purchase_amount(10000, 1000, 24) / 10
+        );
+
+        // Affiliate reward is converted at the sale price, bonus tokens don't count.
+        assert_eq!(
+            contract.preview_claim(0, accounts(4)).affiliate_reward.0,
+            get_purchase_amount(20, 1000, 24)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_PAYOUT_IN_PROGRESS")]
+    fn test_refund_while_purchase_in_flight() {
+        let (mut context, mut contract) = contract_with_ended_subscription(accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_purchase(0, None);
+        contract.claim_refund(0);
+    }
+
+    #[test]
+    fn test_refund_after_unlock_payout() {
+        let (mut context, mut contract) = contract_with_ended_subscription(accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_purchase(0, None);
+        let amount_to_claim = get_purchase_amount(100, 1000, 24);
+        assert_eq!(
+            contract.get_sale_account(0, accounts(2)).claimed.0,
+            amount_to_claim
+        );
+
+        // Callback of the purchase transfer never arrived.
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(1_000_000_001 + 3_600_000_000_000)
+            .build());
+        contract.unlock_payout(0, accounts(2), PayoutKind::Purchase);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_refund(0);
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Successful(vec![]),
+        );
+        assert!(contract.after_refund_purchase(accounts(2), U128(100), 0));
+        let account_sale = contract.get_sale_account(0, accounts(2));
+        assert_eq!(account_sale.refunded.0, 100);
+        assert_eq!(account_sale.claimed.0, amount_to_claim);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_PAYOUT_LOCK_NOT_EXPIRED")]
+    fn test_unlock_payout_in_flight() {
+        let (mut context, mut contract) = contract_with_ended_subscription(accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_purchase(0, None);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.unlock_payout(0, accounts(2), PayoutKind::Purchase);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_PAYOUT_NOT_IN_PROGRESS")]
+    fn test_unlock_payout_not_in_progress() {
+        let (mut context, mut contract) = contract_with_ended_subscription(accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.unlock_payout(0, accounts(2), PayoutKind::Purchase);
+    }
+
+    #[test]
+    fn test_draw_lottery() {
+        let (mut context, mut contract) = contract_with_sale_input(SaleInput {
+            refund_available: true,
+            min_buy: U128(100),
+            max_buy: U128(100),
+            max_amount: U128(200),
+            hard_max_amount_limit: false,
+            sale_type: SaleType::Lottery,
+            ..sale_input(None, 0, 1_000_000_000)
+        });
+        let participants = vec![accounts(2), accounts(4), accounts(5)];
+        for account_id in participants.iter() {
+            register_account(&mut context, &mut contract, account_id.clone());
+            deposit_to_sale(&mut context, &mut contract, 0, account_id.clone(), 100);
+        }
+        testing_env!(context.block_timestamp(1_000_000_001).build());
+        contract.draw_lottery(0);
+        assert!(contract.get_sale(0).lottery_drawn);
+        let winners = contract.get_lottery_winners(0, 0, 10);
+        assert_eq!(winners.len(), 2);
+        let loser = participants
+            .into_iter()
+            .find(|account_id| !winners.contains(account_id))
+            .unwrap();
+
+        // Loser gets the whole deposit back and nothing to claim.
+        let preview = contract.preview_claim(0, loser.clone());
+        assert_eq!(preview.amount_to_claim.0, 0);
+        assert_eq!(preview.refund.0, 100);
+        testing_env!(context.predecessor_account_id(loser.clone()).build());
+        contract.claim_refund(0);
+        assert_eq!(contract.get_sale_account(0, loser).refunded.0, 100);
+
+        // Winner pays the full allocation.
+        let preview = contract.preview_claim(0, winners[0].clone());
+        assert_eq!(
+            preview.amount_to_claim.0,
+            get_purchase_amount(100, 1000, 24)
+        );
+        assert_eq!(preview.refund.0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_LOTTERY_TOO_MANY_WINNERS")]
+    fn test_lottery_too_many_winners() {
+        contract_with_sale_input(SaleInput {
+            min_buy: U128(100),
+            max_buy: U128(100),
+            max_amount: U128(100 * 301),
+            hard_max_amount_limit: false,
+            sale_type: SaleType::Lottery,
+            ..sale_input(None, 0, 1_000_000_000)
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BONDING_CURVE_OVERFLOW")]
+    fn test_bonding_curve_overflow() {
+        // Price doubles every step, 1000 steps can't fit Balance.
+        contract_with_sale_input(SaleInput {
+            sale_type: SaleType::BondingCurve,
+            bonding_curve: Some(BondingCurve::Exponential {
+                step: U128(10),
+                growth: 10000,
+            }),
+            ..sale_input(Some(10000), 0, 1_000_000_000)
+        });
+    }
+
+    /// Dutch auction from 1000 at 1000 down to the floor of 500 at 2000.
+    fn contract_with_dutch_auction(max_amount: Balance) -> (VMContextBuilder, Contract) {
+        contract_with_sale_input(SaleInput {
+            sale_type: SaleType::DutchAuction,
+            floor_price: Some(U128(500)),
+            refund_available: true,
+            ..sale_input(Some(max_amount), 1_000, 2_000)
+        })
+    }
+
+    fn deposit_to_dutch_auction(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        account_id: AccountId,
+        timestamp: u64,
+    ) {
+        register_account(context, contract, account_id.clone());
+        testing_env!(context.block_timestamp(timestamp).build());
+        deposit_to_sale(context, contract, 0, account_id, 100);
+    }
+
+    fn finalize_dutch_auction(context: &mut VMContextBuilder, contract: &mut Contract) {
+        testing_env!(context
+            .block_timestamp(2_001)
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .build());
+        contract.finalize_sale(0, 0, 10);
+    }
+
+    /// Refund is the deposit minus the purchased amount at the clearing price.
+    fn assert_dutch_auction_refund(
+        contract: &Contract,
+        account_id: AccountId,
+        clearing_price: Balance,
+    ) {
+        let account_sale = contract.get_sale_account(0, account_id);
+        assert_eq!(
+            account_sale.refund.0,
+            100 - account_sale.amount_to_claim.0 * clearing_price / 10u128.pow(24)
+        );
+    }
+
+    #[test]
+    fn test_dutch_auction_price() {
+        let (mut context, contract) = contract_with_dutch_auction(10000);
+        for (timestamp, price) in [
+            (0, 1000),
+            (1_000, 1000),
+            (1_500, 750),
+            (2_000, 500),
+            (3_000, 500),
+        ] {
+            testing_env!(context.block_timestamp(timestamp).build());
+            assert_eq!(contract.get_sale_price(0).0, price);
+        }
+    }
+
+    #[test]
+    fn test_dutch_auction_undersubscribed() {
+        let (mut context, mut contract) = contract_with_dutch_auction(10000);
+        deposit_to_dutch_auction(&mut context, &mut contract, accounts(2), 1_500);
+        assert_eq!(
+            contract.get_sale_account(0, accounts(2)).amount_to_claim.0,
+            get_purchase_amount(100, 750, 24)
+        );
+
+        // Auction that didn't sell out clears at the floor.
+        finalize_dutch_auction(&mut context, &mut contract);
+        assert_eq!(contract.get_sale(0).clearing_price, Some(U128(500)));
+        assert_dutch_auction_refund(&contract, accounts(2), 500);
+    }
+
+    #[test]
+    fn test_dutch_auction_filled() {
+        let (mut context, mut contract) = contract_with_dutch_auction(200);
+        deposit_to_dutch_auction(&mut context, &mut contract, accounts(2), 1_200);
+        deposit_to_dutch_auction(&mut context, &mut contract, accounts(4), 1_600);
+
+        // Auction that sold out clears at the price of the last deposit.
+        finalize_dutch_auction(&mut context, &mut contract);
+        assert_eq!(contract.get_sale(0).clearing_price, Some(U128(700)));
+        assert_dutch_auction_refund(&contract, accounts(2), 700);
+        assert_dutch_auction_refund(&contract, accounts(4), 700);
+    }
+
+    #[test]
+    fn test_bonding_curve_price() {
+        let (mut context, mut contract) = contract_with_sale_input(SaleInput {
+            sale_type: SaleType::BondingCurve,
+            bonding_curve: Some(BondingCurve::Linear {
+                step: U128(100),
+                increment: U128(10),
+            }),
+            ..sale_input(Some(10000), 0, 1_000_000_000)
+        });
+        assert_eq!(contract.get_sale_price(0).0, 1000);
+
+        // Every deposit is priced at the middle of the part of the curve it fills.
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit_to_sale(&mut context, &mut contract, 0, accounts(2), 100);
+        assert_eq!(contract.get_sale_price(0).0, 1010);
+        assert_eq!(
+            contract.get_sale_account(0, accounts(2)).amount_to_claim.0,
+            get_purchase_amount(100, 1005, 24)
+        );
+
+        register_account(&mut context, &mut contract, accounts(4));
+        deposit_to_sale(&mut context, &mut contract, 0, accounts(4), 100);
+        assert_eq!(contract.get_sale_price(0).0, 1020);
+        assert_eq!(
+            contract.get_sale_account(0, accounts(4)).amount_to_claim.0,
+            get_purchase_amount(100, 1015, 24)
+        );
+        assert_eq!(contract.get_sale(0).collected_amount.0, 200);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BONDING_CURVE_OVERFLOW")]
+    fn test_update_bonding_curve_price_overflow() {
+        // 1% growth over 100 steps fits the starting price, but not a price close to the maximum.
+        let (mut context, mut contract) = contract_with_sale_input(SaleInput {
+            sale_type: SaleType::BondingCurve,
+            bonding_curve: Some(BondingCurve::Exponential {
+                step: U128(100),
+                growth: 100,
+            }),
+            ..sale_input(Some(10000), 1_000, 1_000_000_000)
+        });
+        testing_env!(context.current_account_id(accounts(0)).build());
+        contract.update_sale_price(0, U128(u128::MAX / 2));
+    }
+
+    #[test]
+    fn test_claim_with_storage_deposit() {
+        let (mut context, mut contract) = contract_with_ended_sale(accounts(2));
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(1000)
+            .build());
+        contract.claim_purchase(0, Some(true));
+        let amount_to_claim = contract.get_sale_account(0, accounts(2)).claimed;
+        let storage_balance_bounds = || {
+            Ok(StorageBalanceBounds {
+                min: U128(100),
+                max: None,
+            })
+        };
+
+        // Only the registration cost goes to the token, the rest is returned to the account.
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(0)
+            .build());
+        contract.after_storage_balance_check(
+            Ok(None),
+            storage_balance_bounds(),
+            accounts(2),
+            amount_to_claim,
+            0,
+            U128(1000),
+        );
+        let receipts = get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, accounts(2));
+        assert_eq!(
+            receipts[0].actions,
+            vec![VmAction::Transfer { deposit: 900 }]
+        );
+        assert_eq!(receipts[1].receiver_id, accounts(3));
+        assert!(matches!(
+            &receipts[1].actions[0],
+            VmAction::FunctionCall { method_name, deposit: 100, .. } if method_name == "storage_deposit"
+        ));
+
+        // Already registered account gets the whole deposit back.
+        testing_env!(context.build());
+        contract.after_storage_balance_check(
+            Ok(Some(StorageBalance {
+                total: U128(100),
+                available: U128(0),
+            })),
+            storage_balance_bounds(),
+            accounts(2),
+            amount_to_claim,
+            0,
+            U128(1000),
+        );
+        let receipts = get_created_receipts();
+        assert_eq!(
+            receipts[0].actions,
+            vec![VmAction::Transfer { deposit: 1000 }]
+        );
+        assert!(matches!(
+            &receipts[1].actions[0],
+            VmAction::FunctionCall { method_name, .. } if method_name == "ft_transfer"
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_REGISTERED_ACCOUNT")]
+    fn test_not_registered() {
+        let (mut context, mut contract) = contract_with_sale();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_on_transfer(
+            accounts(2),
+            U128(100),
+            serde_json::to_string(&SaleDeposit {
+                sale_id: 0,
+                staking_contract: None,
+                staking_contracts: None,
+                staked_amount: None,
+                staking_proof: None,
+            })
+            .unwrap(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NO_SALE")]
+    fn test_no_sale() {
+        let (mut context, mut contract) = contract_with_sale();
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(1000000)
+            .build());
+        contract.join(None);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_on_transfer(
+            accounts(2),
+            U128(100),
+            serde_json::to_string(&SaleDeposit {
+                sale_id: 1,
+                staking_contract: None,
+                staking_contracts: None,
+                staked_amount: None,
+                staking_proof: None,
+            })
+            .unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_create_remove_link() {
+        // Referral links are created by joining with a referrer.
+        let (mut context, mut contract) = contract_with_sale();
+        register_account(&mut context, &mut contract, accounts(2));
+        join_with_referrer(&mut context, &mut contract, accounts(4), accounts(2));
+        assert_eq!(contract.get_account(accounts(4)).referrer, accounts(2));
+        assert_eq!(contract.get_affiliates(accounts(2)).0, vec![accounts(4)]);
+
+        // Link to an unregistered referrer is dropped in favour of the owner.
+        join_with_referrer(&mut context, &mut contract, accounts(5), accounts(3));
+        assert_eq!(contract.get_account(accounts(5)).referrer, accounts(0));
+        assert!(contract.get_affiliates(accounts(0)).0.is_empty());
+    }
+
+    #[test]
+    #[should_panic = "ERR_SALE_NOT_STARTED"]
+    fn test_sale_too_early() {
+        let (mut context, mut contract) = contract_with_sale_info(None, 1_000, 1_000_000);
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit(&mut context, &mut contract, accounts(2));
+    }
+
+    #[test]
+    #[should_panic = "ERR_SALE_DONE"]
+    fn test_sale_too_late() {
+        let (mut context, mut contract) = contract_with_sale_info(None, 1_000, 1_000_000);
+        register_account(&mut context, &mut contract, accounts(2));
+        testing_env!(context
+            .block_timestamp(1_000_001)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.ft_on_transfer(
+            accounts(2),
+            U128(100),
+            serde_json::to_string(&SaleDeposit {
+                sale_id: 0,
+                staking_contract: None,
+                staking_contracts: None,
+                staked_amount: None,
+                staking_proof: None,
+            })
+            .unwrap(),
+        );
+    }
+
+    /// xorshift64 generator to get reproducible random deposit sets.
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn test_allocations_never_exceed_supply() {
+        let mut state = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..200 {
+            let num_accounts = 1 + next_random(&mut state) % 50;
+            let decimals = 1 + (next_random(&mut state) % 18) as u8;
+            let price = 1_000_000_000_000
+                + (next_random(&mut state) as u128) * (next_random(&mut state) as u128)
+                    % 1_000_000_000_000_000_000_000_000_000_000;
+            let deposits: Vec<Balance> = (0..num_accounts)
+                .map(|_| 1 + (next_random(&mut state) as u128) * 1_000_000)
+                .collect();
+            let collected_amount: Balance = deposits.iter().sum();
+            let max_amount = 1 + collected_amount / (1 + (next_random(&mut state) as u128) % 10);
+
+            let supply = get_purchase_amount(max_amount, price, decimals);
+            let allocated: Balance = deposits
+                .iter()
+                .map(|deposit| {
+                    get_amount_to_claim(*deposit, collected_amount, max_amount, price, decimals)
+                })
+                .sum();
+            assert!(allocated <= supply);
+            // Each account loses less than one unit to rounding.
+            assert!(supply - allocated <= num_accounts as u128);
+        }
     }
 }
@@ -31,6 +31,8 @@ impl Contract {
             sales: old_contract.sales,
             num_sales: old_contract.num_sales,
             accounts_old: old_contract.accounts,
+            account_sale_ids: LookupMap::new(StorageKey::AccountSaleIds),
+            owner_referral_policy: OwnerReferralPolicy::Owner,
         }
     }
 
@@ -0,0 +1,59 @@
+use near_sdk::log;
+
+use crate::sale::*;
+use crate::*;
+
+#[near_bindgen]
+impl Contract {
+    // add account to sales index
+    #[private]
+    #[init(ignore_state)]
+    #[allow(dead_code)]
+    pub fn migrate_b0() -> Self {
+        #[derive(BorshDeserialize)]
+        struct OldContract {
+            owner_id: AccountId,
+            join_fee: Balance,
+            referral_fees: Vec<u64>,
+            accounts: UnorderedMap<AccountId, VAccount>,
+            sales: LookupMap<u64, VSale>,
+            num_sales: u64,
+            accounts_old: UnorderedMap<AccountId, AccountOld>,
+        }
+
+        let old_contract: OldContract = env::state_read().expect("Old state doesn't exist");
+
+        Self {
+            owner_id: old_contract.owner_id,
+            join_fee: old_contract.join_fee,
+            referral_fees: old_contract.referral_fees,
+            accounts: old_contract.accounts,
+            sales: old_contract.sales,
+            num_sales: old_contract.num_sales,
+            accounts_old: old_contract.accounts_old,
+            account_sale_ids: LookupMap::new(StorageKey::AccountSaleIds),
+            owner_referral_policy: OwnerReferralPolicy::Owner,
+        }
+    }
+
+    #[private]
+    pub fn migrate_b1(&mut self, sale_id: u64, from_index: u64, limit: u64) {
+        // index accounts of the existing sale
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into_current(sale_id);
+        let keys = sale.account_sales.keys_as_vector();
+        let account_ids: Vec<AccountId> = (from_index
+            ..std::cmp::min(from_index + limit, keys.len()))
+            .map(|index| keys.get(index).unwrap())
+            .collect();
+
+        for account_id in account_ids {
+            self.internal_add_account_sale_id(&account_id, sale_id);
+        }
+
+        log!(
+            "Indexed items: {} of {}",
+            std::cmp::min(from_index + limit, keys.len()),
+            keys.len()
+        );
+    }
+}
@@ -1,11 +1,13 @@
+use std::collections::HashMap;
+
 use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::UnorderedMap;
+use near_sdk::collections::{UnorderedMap, Vector};
 use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
-    ext_contract, log, AccountId, Balance, CryptoHash, PromiseError, PromiseOrValue, PromiseResult,
-    Timestamp,
+    ext_contract, log, serde_json, AccountId, Balance, CryptoHash, PromiseError, PromiseOrValue,
+    PromiseResult, Timestamp,
 };
 
 use crate::token_receiver::*;
@@ -16,6 +18,37 @@ const GAS_NEAR_DEPOSIT: Gas = Gas(5_000_000_000_000);
 const GAS_AFTER_FT_ON_TRANSFER_NEAR_DEPOSIT: Gas = Gas(40_000_000_000_000);
 const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
 const GAS_FOR_AFTER_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_STORAGE_DEPOSIT: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_STORAGE_VIEW: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_AFTER_STORAGE_BALANCE_CHECK: Gas = Gas(40_000_000_000_000);
+const AFFILIATE_LEADERBOARD_SIZE: usize = 10;
+/// Max number of transfers in one distribute_sale call to stay within the gas limit.
+const MAX_DISTRIBUTE_LIMIT: u64 = 10;
+/// Number of the latest payout failures kept per sale.
+const MAX_PAYOUT_FAILURES: u64 = 100;
+/// Max number of lottery winners so draw_lottery stays within the gas limit.
+const MAX_LOTTERY_WINNERS: u128 = 300;
+/// Max number of sales in one claim_purchases call.
+const MAX_CLAIM_SALES: usize = 10;
+/// Gas reserved per claimed sale, covers its own transfer and callback if the token isn't shared.
+const GAS_FOR_CLAIM_PURCHASE: Gas = Gas(25_000_000_000_000);
+/// Max number of accounts in one get_referral_subtree page to stay within the view gas limit.
+const MAX_REFERRAL_SUBTREE_LIMIT: u64 = 100;
+/// Time after which a payout lock can be cleared by the owner. Callbacks resolve within a few
+/// blocks, so a lock this old belongs to a callback that failed.
+const PAYOUT_LOCK_TIMEOUT: Timestamp = 3_600_000_000_000;
+/// Max number of deposits of an account into a sale, every deposit stores its attribution.
+const MAX_DEPOSIT_ATTRIBUTIONS: u64 = 100;
+const BONDING_CURVE_PRECISION: u128 = 1_000_000_000_000_000_000;
+/// 1 => 0.01%
+const BONDING_CURVE_GROWTH_DENOMINATOR: u128 = 10000;
+/// 1 => 0.01% of the purchased tokens.
+const REFEREE_BONUS_DENOMINATOR: u128 = 10000;
+/// 1 => 0.01% of the tokens purchased during the window.
+const BONUS_WINDOW_DENOMINATOR: u128 = 10000;
+
+/// Token, total amount and amounts per sale of the payouts grouped by token.
+type TokenPayouts = Vec<(AccountId, Balance, Vec<(u64, U128)>)>;
 
 uint::construct_uint! {
     pub struct U256(4);
@@ -27,6 +60,32 @@ pub trait ExtWrapNear {
     fn near_deposit(&mut self);
 }
 
+#[ext_contract(ext_storage_management)]
+pub trait ExtStorageManagement {
+    /// Registers the account on the token, paid by the attached deposit.
+    fn storage_deposit(&mut self, account_id: Option<AccountId>, registration_only: Option<bool>);
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance>;
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds;
+}
+
+/// Storage balance of the account registered on the token.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+/// Storage deposit bounds of the token.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
+
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct SaleMetadata {
@@ -66,6 +125,11 @@ pub struct SaleInput {
     pub claim_available: bool,
     /// Is refund available
     pub refund_available: bool,
+    /// Is affiliate reward claim available
+    #[serde(default)]
+    pub affiliate_reward_available: bool,
+    /// Token to pay affiliate rewards in. Deposit token by default.
+    pub affiliate_reward_token: Option<AffiliateRewardToken>,
     /// Token for sale
     pub distribute_token_id: Option<AccountId>,
     /// Number of decimals of token for sale, used to calculate purchase amount
@@ -91,6 +155,16 @@ pub struct SaleInput {
     pub limit_per_transaction: U128,
     /// Sale Type
     pub sale_type: SaleType,
+    /// Price at the end of the dutch auction. Price falls linearly from `price` to it.
+    pub floor_price: Option<U128>,
+    /// Price curve of the bonding curve sale, starts from `price`.
+    pub bonding_curve: Option<BondingCurve>,
+    /// Bonus to tokens of the accounts referred by other accounts than the owner, 1 => 0.01%.
+    pub referee_bonus: Option<u64>,
+    /// Tokens funded for the referee bonuses.
+    pub referee_bonus_supply: Option<U128>,
+    /// Early deposit bonuses of the by amount sale, sorted by the end date.
+    pub bonus_windows: Option<Vec<BonusWindow>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -103,6 +177,8 @@ pub struct SaleOutput {
     pub deposit_token_id: AccountId,
     pub claim_available: bool,
     pub refund_available: bool,
+    pub affiliate_reward_available: bool,
+    pub affiliate_reward_token: AffiliateRewardToken,
     pub distribute_token_id: Option<AccountId>,
     pub distribute_token_decimals: Option<u8>,
     pub min_buy: U128,
@@ -118,22 +194,148 @@ pub struct SaleOutput {
     pub num_account_sales: u64,
     pub sale_type: SaleType,
     pub claim_begun: bool,
+    pub has_staking_snapshot: bool,
+    pub staking_snapshot_root: Option<CryptoHash>,
+    pub lottery_drawn: bool,
+    pub floor_price: Option<U128>,
+    pub clearing_price: Option<U128>,
+    pub sold_amount: U128,
+    pub bonding_curve: Option<BondingCurve>,
+    pub finalized: bool,
+    pub allocated_amount: U128,
+    pub dust_amount: U128,
+    pub dust_claimed: bool,
+    pub referee_bonus: u64,
+    pub referee_bonus_supply: U128,
+    pub referee_bonus_allocated: U128,
+    pub bonus_windows: Vec<BonusWindow>,
+    pub bonus_window_amounts: Vec<U128>,
+    pub owner_referral_policy: OwnerReferralPolicy,
+}
+
+/// Counters of the sale updated on deposits and successful payouts.
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+pub struct SaleStats {
+    /// Affiliate rewards accrued in deposit token.
+    pub affiliate_rewards_accrued: Balance,
+    /// Affiliate rewards paid in affiliate reward token.
+    pub affiliate_rewards_claimed: Balance,
+    pub claimed_amount: Balance,
+    pub num_claimers: u64,
+    pub refunded_amount: Balance,
+    /// Deposits of the accounts with staked amount.
+    pub staked_deposit_amount: Balance,
+    pub non_staked_deposit_amount: Balance,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleStatsOutput {
+    pub affiliate_rewards_accrued: U128,
+    pub affiliate_rewards_claimed: U128,
+    pub claimed_amount: U128,
+    pub num_claimers: u64,
+    pub refunded_amount: U128,
+    /// Collected amount to max amount, 1 => 0.01%.
+    pub oversubscription_ratio: u64,
+    pub staked_deposit_amount: U128,
+    pub non_staked_deposit_amount: U128,
+}
+
+/// Sale without metadata details for listings.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleSummaryOutput {
+    pub sale_id: u64,
+    pub name: String,
+    pub symbol: String,
+    pub logo_url: String,
+    pub deposit_token_id: AccountId,
+    pub distribute_token_id: Option<AccountId>,
+    pub sale_type: SaleType,
+    pub start_date: U64,
+    pub end_date: U64,
+    pub price: U128,
+    pub max_amount: U128,
+    pub collected_amount: U128,
+    pub num_account_sales: u64,
+    pub claim_available: bool,
+    pub refund_available: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum SaleFilter {
+    Active,
+    Upcoming,
+    /// Ended sales with claim or refund available.
+    EndedClaimable,
+    DepositToken(AccountId),
+    Participated(AccountId),
 }
 
 /// Sale information.
 #[derive(BorshSerialize, BorshDeserialize)]
+#[allow(clippy::large_enum_variant)]
 pub enum VSale {
     First(SaleOld),
+    Second(SaleV1),
     Current(Sale),
 }
 
-#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, PartialEq)]
+/// Bonus to the tokens purchased before the window end and after the previous window.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BonusWindow {
+    pub end_date: U64,
+    /// 1 => 0.01%
+    pub bonus: u64,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(crate = "near_sdk::serde")]
 pub enum SaleType {
     /// First buyers receive tokens. Sale stops when collected_amount reached
     ByAmount,
     /// Unlimited purchase, proportional distribution. Sale stops when end_date reached
     BySubscription,
+    /// Every account deposits fixed allocation (min_buy == max_buy). After end_date
+    /// max_amount / max_buy winners are drawn, the rest of accounts get full refund
+    Lottery,
+    /// Price falls from price to floor_price during the sale. Everyone pays the price
+    /// of the last deposit, overpaid amount is refunded
+    DutchAuction,
+    /// Price grows with collected_amount along bonding_curve. Every deposit buys at the price
+    /// in the middle of the deposit
+    BondingCurve,
+}
+
+impl SaleType {
+    /// Sale types where part of the deposit can be returned after the sale.
+    pub fn is_refundable(&self) -> bool {
+        matches!(
+            self,
+            SaleType::BySubscription | SaleType::Lottery | SaleType::DutchAuction
+        )
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum AffiliateRewardToken {
+    /// Rewards are paid in deposit token out of the sale proceeds
+    DepositToken,
+    /// Rewards are converted with the sale price and paid in distribute token
+    DistributeToken,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum BondingCurve {
+    /// Price grows by `increment` for every `step` of collected amount.
+    Linear { step: U128, increment: U128 },
+    /// Price grows by `growth` (1 => 0.01%) for every full `step` of collected amount.
+    Exponential { step: U128, growth: u64 },
 }
 
 #[derive(BorshSerialize, BorshDeserialize)]
@@ -156,6 +358,33 @@ pub struct SaleOld {
     pub account_sales: UnorderedMap<AccountId, VSaleAccount>,
 }
 
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct SaleV1 {
+    pub metadata: SaleMetadata,
+    pub staking_contracts: Vec<AccountId>,
+    pub min_near_deposit: Balance,
+    pub deposit_token_id: AccountId,
+    pub claim_available: bool,
+    pub refund_available: bool,
+    pub distribute_token_id: Option<AccountId>,
+    pub distribute_token_decimals: Option<u8>,
+    pub min_buy: Balance,
+    pub max_buy: Balance,
+    pub max_amount: Balance,
+    pub hard_max_amount_limit: bool,
+    pub start_date: Timestamp,
+    pub end_date: Timestamp,
+    pub price: Balance,
+    pub whitelist_hash: Option<CryptoHash>,
+    pub limit_per_transaction: Balance,
+
+    pub collected_amount: Balance,
+    pub account_sales: UnorderedMap<AccountId, VSaleAccount>,
+    pub account_affiliate_rewards: UnorderedMap<AccountId, VAffiliateRewardAccount>,
+    pub sale_type: SaleType,
+    pub claim_begun: bool,
+}
+
 #[derive(BorshSerialize, BorshDeserialize)]
 pub struct Sale {
     pub metadata: SaleMetadata,
@@ -164,6 +393,8 @@ pub struct Sale {
     pub deposit_token_id: AccountId,
     pub claim_available: bool,
     pub refund_available: bool,
+    pub affiliate_reward_available: bool,
+    pub affiliate_reward_token: AffiliateRewardToken,
     pub distribute_token_id: Option<AccountId>,
     pub distribute_token_decimals: Option<u8>,
     pub min_buy: Balance,
@@ -182,99 +413,199 @@ pub struct Sale {
     pub account_affiliate_rewards: UnorderedMap<AccountId, VAffiliateRewardAccount>,
     pub sale_type: SaleType,
     pub claim_begun: bool,
+    /// Staked amounts uploaded by the owner. Replaces staking contract calls on deposit.
+    pub staking_snapshot: Option<LookupMap<AccountId, Balance>>,
+    /// Merkle root of (account, staked amount) pairs. Replaces staking contract calls on deposit.
+    pub staking_snapshot_root: Option<CryptoHash>,
+    /// Drawn winners of the lottery sale.
+    pub lottery_winners: Option<UnorderedSet<AccountId>>,
+    pub floor_price: Option<Balance>,
+    /// Price of the last dutch auction deposit, paid by every account.
+    pub clearing_price: Option<Balance>,
+    /// Amount of distribute token sold at deposit time.
+    pub sold_amount: Balance,
+    pub bonding_curve: Option<BondingCurve>,
+    /// All accounts have their allocation and refund fixed.
+    pub finalized: bool,
+    /// Number of sale accounts processed by finalize_sale.
+    pub num_finalized_accounts: u64,
+    /// Sum of allocations of the accounts processed by finalize_sale.
+    pub allocated_amount: Balance,
+    /// Distributable supply left after rounding down the allocations. Known once finalized.
+    pub dust_amount: Balance,
+    pub dust_claimed: bool,
+    /// Affiliates with the biggest accrued reward, sorted in descending order.
+    pub top_affiliates: Vec<(AccountId, Balance)>,
+    /// Accounts whose pushed distribution failed. Purchase stays claimable for them.
+    pub distribution_failures: UnorderedSet<AccountId>,
+    /// Latest failed payouts, oldest are overwritten once the log is full.
+    pub payout_failures: Vector<PayoutFailure>,
+    pub num_payout_failures: u64,
+    /// Payouts sent and waiting for the transfer callback, with the time they were sent.
+    pub payouts_in_flight: LookupMap<(AccountId, PayoutKind), Timestamp>,
+    /// Counted from the deposits and payouts made after the stats were added, so they start at
+    /// zero for the sales created before.
+    pub stats: SaleStats,
+    /// Deposits of every account with affiliate rewards accrued for them.
+    pub deposit_attributions: UnorderedMap<AccountId, Vector<DepositAttribution>>,
+    /// 1 => 0.01%
+    pub referee_bonus: u64,
+    pub referee_bonus_supply: Balance,
+    pub referee_bonus_allocated: Balance,
+    pub bonus_windows: Vec<BonusWindow>,
+    /// Deposits made during each of the bonus windows.
+    pub bonus_window_amounts: Vec<Balance>,
+    /// Owner referral policy at the sale creation, later updates don't change it.
+    pub owner_referral_policy: OwnerReferralPolicy,
 }
 
-impl From<VSale> for Sale {
-    fn from(v_sale: VSale) -> Self {
-        match v_sale {
-            VSale::First(sale) => Sale {
-                metadata: sale.metadata,
-                staking_contracts: sale.staking_contracts,
-                min_near_deposit: sale.min_near_deposit,
-                deposit_token_id: sale.deposit_token_id,
-                claim_available: false,
-                refund_available: false,
-                distribute_token_id: None,
-                distribute_token_decimals: None,
-                min_buy: sale.min_buy,
-                max_buy: sale.max_buy,
-                max_amount: sale.max_amount.unwrap_or_default(),
-                hard_max_amount_limit: sale.hard_max_amount_limit,
-                start_date: sale.start_date,
-                end_date: sale.end_date,
-                price: sale.price,
-                whitelist_hash: sale.whitelist_hash,
-                limit_per_transaction: sale.limit_per_transaction,
-                collected_amount: sale.collected_amount,
-                account_sales: sale.account_sales,
-                account_affiliate_rewards: UnorderedMap::new(StorageKey::AccountAffiliateRewards {
-                    sale_id: 0,
-                }),
-                sale_type: SaleType::ByAmount,
-                claim_begun: false,
-            },
-            VSale::Current(sale) => sale,
+impl From<SaleOld> for SaleV1 {
+    fn from(sale: SaleOld) -> Self {
+        SaleV1 {
+            metadata: sale.metadata,
+            staking_contracts: sale.staking_contracts,
+            min_near_deposit: sale.min_near_deposit,
+            deposit_token_id: sale.deposit_token_id,
+            claim_available: false,
+            refund_available: false,
+            distribute_token_id: None,
+            distribute_token_decimals: None,
+            min_buy: sale.min_buy,
+            max_buy: sale.max_buy,
+            max_amount: sale.max_amount.unwrap_or_default(),
+            hard_max_amount_limit: sale.hard_max_amount_limit,
+            start_date: sale.start_date,
+            end_date: sale.end_date,
+            price: sale.price,
+            whitelist_hash: sale.whitelist_hash,
+            limit_per_transaction: sale.limit_per_transaction,
+            collected_amount: sale.collected_amount,
+            account_sales: sale.account_sales,
+            account_affiliate_rewards: UnorderedMap::new(StorageKey::AccountAffiliateRewards {
+                sale_id: 0,
+            }),
+            sale_type: SaleType::ByAmount,
+            claim_begun: false,
         }
     }
 }
 
-impl From<VSale> for SaleOutput {
-    fn from(v_sale: VSale) -> Self {
-        match v_sale {
-            VSale::First(sale) => SaleOutput {
-                sale_id: None,
-                metadata: sale.metadata,
-                staking_contracts: sale.staking_contracts,
-                min_near_deposit: U128(sale.min_near_deposit),
-                deposit_token_id: sale.deposit_token_id,
-                claim_available: false,
-                refund_available: false,
-                distribute_token_id: None,
-                distribute_token_decimals: None,
-                min_buy: U128(sale.min_buy),
-                max_buy: U128(sale.max_buy),
-                max_amount: U128(sale.max_amount.unwrap_or_default()),
-                hard_max_amount_limit: sale.hard_max_amount_limit,
-                start_date: U64(sale.start_date),
-                end_date: U64(sale.end_date),
-                price: U128(sale.price),
-                whitelist_hash: sale.whitelist_hash,
-                limit_per_transaction: sale.limit_per_transaction.into(),
-                collected_amount: U128(sale.collected_amount),
-                num_account_sales: sale.account_sales.keys_as_vector().len(),
-                sale_type: SaleType::ByAmount,
-                claim_begun: false,
-            },
-            VSale::Current(sale) => SaleOutput {
-                sale_id: None,
-                metadata: sale.metadata,
-                staking_contracts: sale.staking_contracts,
-                min_near_deposit: U128(sale.min_near_deposit),
-                deposit_token_id: sale.deposit_token_id,
-                claim_available: sale.claim_available,
-                refund_available: sale.refund_available,
-                distribute_token_id: sale.distribute_token_id,
-                distribute_token_decimals: sale.distribute_token_decimals,
-                min_buy: U128(sale.min_buy),
-                max_buy: U128(sale.max_buy),
-                max_amount: U128(sale.max_amount),
-                hard_max_amount_limit: sale.hard_max_amount_limit,
-                start_date: U64(sale.start_date),
-                end_date: U64(sale.end_date),
-                price: U128(sale.price),
-                whitelist_hash: sale.whitelist_hash,
-                limit_per_transaction: sale.limit_per_transaction.into(),
-                collected_amount: U128(sale.collected_amount),
-                num_account_sales: sale.account_sales.keys_as_vector().len(),
-                sale_type: sale.sale_type,
-                claim_begun: sale.claim_begun,
-            },
+impl VSale {
+    /// Upgrades the stored sale. Collections added later are keyed by `sale_id`.
+    pub fn into_current(self, sale_id: u64) -> Sale {
+        let sale = match self {
+            VSale::First(sale) => sale.into(),
+            VSale::Second(sale) => sale,
+            VSale::Current(sale) => return sale,
+        };
+        Sale {
+            metadata: sale.metadata,
+            staking_contracts: sale.staking_contracts,
+            min_near_deposit: sale.min_near_deposit,
+            deposit_token_id: sale.deposit_token_id,
+            claim_available: sale.claim_available,
+            refund_available: sale.refund_available,
+            affiliate_reward_available: false,
+            affiliate_reward_token: AffiliateRewardToken::DepositToken,
+            distribute_token_id: sale.distribute_token_id,
+            distribute_token_decimals: sale.distribute_token_decimals,
+            min_buy: sale.min_buy,
+            max_buy: sale.max_buy,
+            max_amount: sale.max_amount,
+            hard_max_amount_limit: sale.hard_max_amount_limit,
+            start_date: sale.start_date,
+            end_date: sale.end_date,
+            price: sale.price,
+            whitelist_hash: sale.whitelist_hash,
+            limit_per_transaction: sale.limit_per_transaction,
+            collected_amount: sale.collected_amount,
+            account_sales: sale.account_sales,
+            account_affiliate_rewards: sale.account_affiliate_rewards,
+            sale_type: sale.sale_type,
+            claim_begun: sale.claim_begun,
+            staking_snapshot: None,
+            staking_snapshot_root: None,
+            lottery_winners: None,
+            floor_price: None,
+            clearing_price: None,
+            sold_amount: 0,
+            bonding_curve: None,
+            finalized: false,
+            num_finalized_accounts: 0,
+            allocated_amount: 0,
+            dust_amount: 0,
+            dust_claimed: false,
+            top_affiliates: vec![],
+            distribution_failures: UnorderedSet::new(StorageKey::DistributionFailures { sale_id }),
+            payout_failures: Vector::new(StorageKey::PayoutFailures { sale_id }),
+            num_payout_failures: 0,
+            payouts_in_flight: LookupMap::new(StorageKey::PayoutsInFlight { sale_id }),
+            stats: SaleStats::default(),
+            deposit_attributions: UnorderedMap::new(StorageKey::DepositAttributions { sale_id }),
+            referee_bonus: 0,
+            referee_bonus_supply: 0,
+            referee_bonus_allocated: 0,
+            bonus_windows: vec![],
+            bonus_window_amounts: vec![],
+            owner_referral_policy: OwnerReferralPolicy::Owner,
+        }
+    }
+}
+
+impl From<Sale> for SaleOutput {
+    fn from(sale: Sale) -> Self {
+        SaleOutput {
+            sale_id: None,
+            metadata: sale.metadata,
+            staking_contracts: sale.staking_contracts,
+            min_near_deposit: U128(sale.min_near_deposit),
+            deposit_token_id: sale.deposit_token_id,
+            claim_available: sale.claim_available,
+            refund_available: sale.refund_available,
+            affiliate_reward_available: sale.affiliate_reward_available,
+            affiliate_reward_token: sale.affiliate_reward_token,
+            distribute_token_id: sale.distribute_token_id,
+            distribute_token_decimals: sale.distribute_token_decimals,
+            min_buy: U128(sale.min_buy),
+            max_buy: U128(sale.max_buy),
+            max_amount: U128(sale.max_amount),
+            hard_max_amount_limit: sale.hard_max_amount_limit,
+            start_date: U64(sale.start_date),
+            end_date: U64(sale.end_date),
+            price: U128(sale.price),
+            whitelist_hash: sale.whitelist_hash,
+            limit_per_transaction: sale.limit_per_transaction.into(),
+            collected_amount: U128(sale.collected_amount),
+            num_account_sales: sale.account_sales.keys_as_vector().len(),
+            sale_type: sale.sale_type,
+            claim_begun: sale.claim_begun,
+            has_staking_snapshot: sale.staking_snapshot.is_some(),
+            staking_snapshot_root: sale.staking_snapshot_root,
+            lottery_drawn: sale.lottery_winners.is_some(),
+            floor_price: sale.floor_price.map(U128),
+            clearing_price: sale.clearing_price.map(U128),
+            sold_amount: U128(sale.sold_amount),
+            bonding_curve: sale.bonding_curve,
+            finalized: sale.finalized,
+            allocated_amount: U128(sale.allocated_amount),
+            dust_amount: U128(sale.dust_amount),
+            dust_claimed: sale.dust_claimed,
+            referee_bonus: sale.referee_bonus,
+            referee_bonus_supply: U128(sale.referee_bonus_supply),
+            referee_bonus_allocated: U128(sale.referee_bonus_allocated),
+            bonus_windows: sale.bonus_windows,
+            bonus_window_amounts: sale.bonus_window_amounts.into_iter().map(U128).collect(),
+            owner_referral_policy: sale.owner_referral_policy,
         }
     }
 }
 
 impl VSale {
-    pub fn new(sale_id: u64, sale_input: SaleInput) -> Self {
+    pub fn new(
+        sale_id: u64,
+        sale_input: SaleInput,
+        owner_referral_policy: OwnerReferralPolicy,
+    ) -> Self {
         Self::Current(Sale {
             metadata: sale_input.metadata,
             staking_contracts: sale_input.staking_contracts,
@@ -282,6 +613,10 @@ impl VSale {
             deposit_token_id: sale_input.deposit_token_id,
             claim_available: sale_input.claim_available,
             refund_available: sale_input.refund_available,
+            affiliate_reward_available: sale_input.affiliate_reward_available,
+            affiliate_reward_token: sale_input
+                .affiliate_reward_token
+                .unwrap_or(AffiliateRewardToken::DepositToken),
             distribute_token_id: sale_input.distribute_token_id,
             distribute_token_decimals: sale_input.distribute_token_decimals,
             min_buy: sale_input.min_buy.0,
@@ -300,14 +635,439 @@ impl VSale {
             }),
             sale_type: sale_input.sale_type,
             claim_begun: false,
+            staking_snapshot: None,
+            staking_snapshot_root: None,
+            lottery_winners: None,
+            floor_price: sale_input.floor_price.map(|floor_price| floor_price.0),
+            clearing_price: None,
+            sold_amount: 0,
+            bonding_curve: sale_input.bonding_curve,
+            finalized: false,
+            num_finalized_accounts: 0,
+            allocated_amount: 0,
+            dust_amount: 0,
+            dust_claimed: false,
+            top_affiliates: vec![],
+            distribution_failures: UnorderedSet::new(StorageKey::DistributionFailures { sale_id }),
+            payout_failures: Vector::new(StorageKey::PayoutFailures { sale_id }),
+            num_payout_failures: 0,
+            payouts_in_flight: LookupMap::new(StorageKey::PayoutsInFlight { sale_id }),
+            stats: SaleStats::default(),
+            deposit_attributions: UnorderedMap::new(StorageKey::DepositAttributions { sale_id }),
+            referee_bonus: sale_input.referee_bonus.unwrap_or(0),
+            referee_bonus_supply: sale_input
+                .referee_bonus_supply
+                .map(|referee_bonus_supply| referee_bonus_supply.0)
+                .unwrap_or(0),
+            referee_bonus_allocated: 0,
+            bonus_windows: sale_input.bonus_windows.unwrap_or_default(),
+            bonus_window_amounts: vec![],
+            owner_referral_policy,
         })
     }
 }
 
+impl Sale {
+    /// Fixes allocation and refund of the given account once the sale is over.
+    /// Referred accounts get the referee bonus while the bonus supply lasts.
+    pub(crate) fn calculate_account_purchase(
+        &mut self,
+        account_id: &AccountId,
+        account_sale: &mut SaleAccount,
+        is_referred: bool,
+    ) {
+        let distribute_token_decimals_value = self
+            .distribute_token_decimals
+            .expect("ERR_NO_TOKEN_DECIMALS");
+        assert_ne!(account_sale.amount.0, 0, "ERR_NO_ALLOCATION");
+
+        let deposit_amount = account_sale.amount.0;
+
+        let amount_to_claim: u128 = if account_sale.claimed.0 == 0 {
+            if self.sale_type == SaleType::DutchAuction || self.sale_type == SaleType::BondingCurve
+            {
+                // Purchased at deposit time.
+                account_sale.amount_to_claim.0 - account_sale.referee_bonus.0
+            } else if self.sale_type == SaleType::Lottery {
+                let lottery_winners = self
+                    .lottery_winners
+                    .as_ref()
+                    .expect("ERR_LOTTERY_NOT_DRAWN");
+                if lottery_winners.contains(account_id) {
+                    get_purchase_amount(deposit_amount, self.price, distribute_token_decimals_value)
+                } else {
+                    0
+                }
+            } else if self.sale_type == SaleType::ByAmount {
+                let window_amounts: Vec<Balance> = account_sale
+                    .window_amounts
+                    .iter()
+                    .map(|amount| amount.0)
+                    .collect();
+                get_purchase_amount(deposit_amount, self.price, distribute_token_decimals_value)
+                    + self.get_bonus_windows_amount(&window_amounts)
+            } else if self.collected_amount <= self.max_amount {
+                get_purchase_amount(deposit_amount, self.price, distribute_token_decimals_value)
+            } else {
+                get_amount_to_claim(
+                    deposit_amount,
+                    self.collected_amount,
+                    self.max_amount,
+                    self.price,
+                    distribute_token_decimals_value,
+                )
+            }
+        } else {
+            account_sale.claimed.0 - account_sale.referee_bonus.0
+        };
+        if account_sale.amount_to_claim.0 == 0 && amount_to_claim > 0 {
+            account_sale.amount_to_claim = U128(amount_to_claim);
+        }
+        if is_referred
+            && self.referee_bonus > 0
+            && account_sale.referee_bonus.0 == 0
+            && account_sale.claimed.0 == 0
+            && amount_to_claim > 0
+        {
+            let referee_bonus = std::cmp::min(
+                (U256::from(amount_to_claim) * U256::from(self.referee_bonus)
+                    / U256::from(REFEREE_BONUS_DENOMINATOR))
+                .as_u128(),
+                self.referee_bonus_supply - self.referee_bonus_allocated,
+            );
+            account_sale.referee_bonus = U128(referee_bonus);
+            account_sale.amount_to_claim = U128(account_sale.amount_to_claim.0 + referee_bonus);
+            self.referee_bonus_allocated += referee_bonus;
+        }
+        if self.sale_type.is_refundable() {
+            let purchase_price = if self.sale_type == SaleType::DutchAuction {
+                self.get_clearing_price()
+            } else {
+                self.price
+            };
+            let client_purchase_amount: u128 = (U256::from(amount_to_claim)
+                * U256::from(purchase_price)
+                / U256::from(u128::pow(10, distribute_token_decimals_value as u32)))
+            .as_u128();
+            if account_sale.refund.0 == 0 && deposit_amount > client_purchase_amount {
+                account_sale.refund = U128(deposit_amount - client_purchase_amount);
+            }
+        }
+    }
+
+    /// Bonus tokens for the amounts deposited during each of the bonus windows.
+    pub(crate) fn get_bonus_windows_amount(&self, window_amounts: &[Balance]) -> Balance {
+        let distribute_token_decimals_value = self
+            .distribute_token_decimals
+            .expect("ERR_NO_TOKEN_DECIMALS");
+        self.bonus_windows
+            .iter()
+            .zip(window_amounts.iter())
+            .map(|(bonus_window, window_amount)| {
+                (U256::from(get_purchase_amount(
+                    *window_amount,
+                    self.price,
+                    distribute_token_decimals_value,
+                )) * U256::from(bonus_window.bonus)
+                    / U256::from(BONUS_WINDOW_DENOMINATOR))
+                .as_u128()
+            })
+            .sum()
+    }
+
+    /// Index of the bonus window the deposit made at the given time falls into.
+    pub(crate) fn get_bonus_window_index(&self, timestamp: Timestamp) -> Option<usize> {
+        self.bonus_windows
+            .iter()
+            .position(|bonus_window| timestamp <= bonus_window.end_date.0)
+    }
+
+    /// Allocations are being fixed by finalize_sale, so the price can't change anymore.
+    pub(crate) fn is_finalization_begun(&self) -> bool {
+        self.finalized || self.num_finalized_accounts > 0
+    }
+
+    /// Allocation of the accounts can be calculated with the current sale settings.
+    pub(crate) fn is_purchase_calculable(&self) -> bool {
+        self.price != 0
+            && self.distribute_token_decimals.is_some()
+            && (self.sale_type != SaleType::Lottery || self.lottery_winners.is_some())
+    }
+
+    pub(crate) fn matches_filter(&self, filter: &SaleFilter, timestamp: Timestamp) -> bool {
+        match filter {
+            SaleFilter::Active => self.start_date <= timestamp && timestamp <= self.end_date,
+            SaleFilter::Upcoming => timestamp < self.start_date,
+            SaleFilter::EndedClaimable => {
+                timestamp > self.end_date && (self.claim_available || self.refund_available)
+            }
+            SaleFilter::DepositToken(token_id) => &self.deposit_token_id == token_id,
+            SaleFilter::Participated(account_id) => self.account_sales.get(account_id).is_some(),
+        }
+    }
+
+    pub(crate) fn get_summary(&self, sale_id: u64) -> SaleSummaryOutput {
+        SaleSummaryOutput {
+            sale_id,
+            name: self.metadata.name.clone(),
+            symbol: self.metadata.symbol.clone(),
+            logo_url: self.metadata.logo_url.clone(),
+            deposit_token_id: self.deposit_token_id.clone(),
+            distribute_token_id: self.distribute_token_id.clone(),
+            sale_type: self.sale_type.clone(),
+            start_date: U64(self.start_date),
+            end_date: U64(self.end_date),
+            price: U128(self.price),
+            max_amount: U128(self.max_amount),
+            collected_amount: U128(self.collected_amount),
+            num_account_sales: self.account_sales.len(),
+            claim_available: self.claim_available,
+            refund_available: self.refund_available,
+        }
+    }
+
+    /// Amount of distribute token that allocations of all accounts can't exceed.
+    pub(crate) fn get_distributable_supply(&self) -> Balance {
+        if self.sale_type == SaleType::ByAmount {
+            self.get_purchased_supply() + self.get_bonus_windows_amount(&self.bonus_window_amounts)
+        } else {
+            self.get_purchased_supply()
+        }
+    }
+
+    /// Tokens purchased at the sale price, without the bonus window tokens.
+    pub(crate) fn get_purchased_supply(&self) -> Balance {
+        let distribute_token_decimals_value = self
+            .distribute_token_decimals
+            .expect("ERR_NO_TOKEN_DECIMALS");
+        match self.sale_type {
+            SaleType::ByAmount => get_purchase_amount(
+                self.collected_amount,
+                self.price,
+                distribute_token_decimals_value,
+            ),
+            SaleType::BySubscription => get_purchase_amount(
+                std::cmp::min(self.collected_amount, self.max_amount),
+                self.price,
+                distribute_token_decimals_value,
+            ),
+            SaleType::Lottery => {
+                let num_winners = self
+                    .lottery_winners
+                    .as_ref()
+                    .map(|lottery_winners| lottery_winners.len())
+                    .unwrap_or(0);
+                get_purchase_amount(self.max_buy, self.price, distribute_token_decimals_value)
+                    * num_winners as u128
+            }
+            SaleType::DutchAuction | SaleType::BondingCurve => self.sold_amount,
+        }
+    }
+
+    /// Part of collected amount that purchased tokens, the rest is refunded.
+    pub(crate) fn get_filled_amount(&self) -> Balance {
+        match self.sale_type {
+            SaleType::ByAmount | SaleType::BondingCurve => self.collected_amount,
+            SaleType::BySubscription => std::cmp::min(self.collected_amount, self.max_amount),
+            SaleType::Lottery => {
+                let lottery_winners = self
+                    .lottery_winners
+                    .as_ref()
+                    .expect("ERR_LOTTERY_NOT_DRAWN");
+                self.max_buy * lottery_winners.len() as u128
+            }
+            SaleType::DutchAuction => {
+                let distribute_token_decimals_value = self
+                    .distribute_token_decimals
+                    .expect("ERR_NO_TOKEN_DECIMALS");
+                let filled_amount = (U256::from(self.sold_amount)
+                    * U256::from(self.get_clearing_price())
+                    / U256::from(u128::pow(10, distribute_token_decimals_value as u32)))
+                .as_u128();
+                std::cmp::min(self.collected_amount, filled_amount)
+            }
+        }
+    }
+
+    /// Affiliate reward for the accrued amount. Accrued amount is in deposit token, it is scaled
+    /// down by the filled part of the sale and converted to the affiliate reward token.
+    pub(crate) fn get_affiliate_reward(&self, accrued_amount: Balance) -> Balance {
+        if self.collected_amount == 0 {
+            return 0;
+        }
+        let filled_amount = self.get_filled_amount();
+        let reward = U256::from(accrued_amount) * U256::from(filled_amount)
+            / U256::from(self.collected_amount);
+        match self.affiliate_reward_token {
+            AffiliateRewardToken::DepositToken => reward.as_u128(),
+            AffiliateRewardToken::DistributeToken => {
+                if filled_amount == 0 {
+                    return 0;
+                }
+                // Average price of the sale: purchased supply for the filled amount.
+                (reward * U256::from(self.get_purchased_supply()) / U256::from(filled_amount))
+                    .as_u128()
+            }
+        }
+    }
+
+    /// Affiliate reward that can be claimed right now.
+    pub(crate) fn get_claimable_affiliate_reward(
+        &self,
+        account_affiliate_reward: &AffiliateRewardAccount,
+    ) -> Balance {
+        if !self.affiliate_reward_available
+            || account_affiliate_reward.claimed.0 > 0
+            || env::block_timestamp() <= self.end_date
+            || (self.sale_type == SaleType::Lottery && self.lottery_winners.is_none())
+        {
+            return 0;
+        }
+        self.get_affiliate_reward(account_affiliate_reward.amount.0)
+    }
+
+    /// Keeps the given affiliate among top affiliates if accrued reward is big enough.
+    pub(crate) fn update_top_affiliates(&mut self, account_id: &AccountId, amount: Balance) {
+        self.top_affiliates
+            .retain(|(top_account_id, _)| top_account_id != account_id);
+        let position = self
+            .top_affiliates
+            .iter()
+            .position(|(_, top_amount)| *top_amount < amount)
+            .unwrap_or(self.top_affiliates.len());
+        if position < AFFILIATE_LEADERBOARD_SIZE {
+            self.top_affiliates
+                .insert(position, (account_id.clone(), amount));
+            self.top_affiliates.truncate(AFFILIATE_LEADERBOARD_SIZE);
+        }
+    }
+
+    pub(crate) fn record_payout_failure(
+        &mut self,
+        account_id: &AccountId,
+        kind: PayoutKind,
+        amount: Balance,
+        reason: &str,
+    ) {
+        let payout_failure = PayoutFailure {
+            account_id: account_id.clone(),
+            kind,
+            amount: U128(amount),
+            timestamp: U64(env::block_timestamp()),
+            reason: reason.to_string(),
+            retried: false,
+        };
+        if self.payout_failures.len() < MAX_PAYOUT_FAILURES {
+            self.payout_failures.push(&payout_failure);
+        } else {
+            self.payout_failures.replace(
+                self.num_payout_failures % MAX_PAYOUT_FAILURES,
+                &payout_failure,
+            );
+        }
+        self.num_payout_failures += 1;
+    }
+
+    /// Purchase and refund of the account block each other, affiliate reward is independent.
+    pub(crate) fn is_payout_in_flight(&self, account_id: &AccountId, kind: PayoutKind) -> bool {
+        let in_flight = |kind| {
+            self.payouts_in_flight
+                .contains_key(&(account_id.clone(), kind))
+        };
+        match kind {
+            PayoutKind::Purchase | PayoutKind::Refund => {
+                in_flight(PayoutKind::Purchase) || in_flight(PayoutKind::Refund)
+            }
+            PayoutKind::AffiliateReward => in_flight(PayoutKind::AffiliateReward),
+        }
+    }
+
+    pub(crate) fn lock_payout(&mut self, account_id: &AccountId, kind: PayoutKind) {
+        assert!(
+            !self.is_payout_in_flight(account_id, kind),
+            "ERR_PAYOUT_IN_PROGRESS"
+        );
+        self.payouts_in_flight
+            .insert(&(account_id.clone(), kind), &env::block_timestamp());
+    }
+
+    pub(crate) fn get_affiliate_reward_token_id(&self) -> AccountId {
+        match self.affiliate_reward_token {
+            AffiliateRewardToken::DepositToken => self.deposit_token_id.clone(),
+            AffiliateRewardToken::DistributeToken => {
+                self.distribute_token_id.clone().expect("ERR_NO_TOKEN_ID")
+            }
+        }
+    }
+
+    /// Price of the dutch auction at the given moment, falls linearly from `price` to `floor_price`.
+    pub(crate) fn get_dutch_auction_price(&self, timestamp: Timestamp) -> Balance {
+        let floor_price = self.floor_price.expect("ERR_NO_FLOOR_PRICE");
+        if timestamp <= self.start_date {
+            return self.price;
+        }
+        if timestamp >= self.end_date {
+            return floor_price;
+        }
+        self.price
+            - (U256::from(self.price - floor_price) * U256::from(timestamp - self.start_date)
+                / U256::from(self.end_date - self.start_date))
+            .as_u128()
+    }
+
+    /// Price paid by every account of the dutch auction. Undersubscribed auction clears at the
+    /// price of the sale end.
+    pub(crate) fn get_clearing_price(&self) -> Balance {
+        if self.collected_amount < self.max_amount {
+            self.get_dutch_auction_price(self.end_date)
+        } else {
+            self.clearing_price.unwrap_or(self.price)
+        }
+    }
+
+    /// Price of the bonding curve sale at the given collected amount.
+    pub(crate) fn get_bonding_curve_price(&self, collected_amount: Balance) -> Balance {
+        get_bonding_curve_price(
+            self.bonding_curve.as_ref().expect("ERR_NO_BONDING_CURVE"),
+            self.price,
+            collected_amount,
+        )
+        .expect("ERR_BONDING_CURVE_OVERFLOW")
+    }
+
+    /// Returns staked amount of the given account according to the sale staking snapshot.
+    /// Merkle proof is required if snapshot is registered as a root.
+    pub(crate) fn get_snapshot_staked_amount(
+        &self,
+        account_id: &AccountId,
+        staked_amount: Option<U128>,
+        staking_proof: Option<Vec<CryptoHash>>,
+    ) -> Balance {
+        if let Some(staking_snapshot_root) = self.staking_snapshot_root {
+            let staked_amount = staked_amount.expect("ERR_MUST_HAVE_STAKED_AMOUNT").0;
+            assert!(
+                verify_staking_proof(
+                    staking_snapshot_root,
+                    account_id,
+                    staked_amount,
+                    staking_proof.unwrap_or_default(),
+                ),
+                "ERR_WRONG_STAKING_PROOF"
+            );
+            staked_amount
+        } else if let Some(staking_snapshot) = &self.staking_snapshot {
+            staking_snapshot.get(account_id).unwrap_or(0)
+        } else {
+            0
+        }
+    }
+}
+
 /// Account deposits for the a sale.
 #[derive(BorshSerialize, BorshDeserialize)]
 pub enum VSaleAccount {
     First(SaleAccountOld),
+    Second(SaleAccountV1),
     Current(SaleAccount),
 }
 
@@ -323,14 +1083,28 @@ pub struct SaleAccountOld {
     pub amount: U128,
 }
 
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleAccountV1 {
+    pub amount: U128,
+    pub amount_to_claim: U128,
+    pub claimed: U128,
+    pub refund: U128,
+    pub refunded: U128,
+}
+
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct SaleAccount {
     pub amount: U128,
+    /// Includes the referee bonus.
     pub amount_to_claim: U128,
     pub claimed: U128,
     pub refund: U128,
     pub refunded: U128,
+    pub referee_bonus: U128,
+    /// Deposits made during each of the bonus windows, included in `amount`.
+    pub window_amounts: Vec<U128>,
 }
 
 impl From<VSaleAccount> for SaleAccount {
@@ -342,12 +1116,52 @@ impl From<VSaleAccount> for SaleAccount {
                 claimed: U128(0),
                 refund: U128(0),
                 refunded: U128(0),
+                referee_bonus: U128(0),
+                window_amounts: vec![],
+            },
+            VSaleAccount::Second(account_sale) => SaleAccount {
+                amount: account_sale.amount,
+                amount_to_claim: account_sale.amount_to_claim,
+                claimed: account_sale.claimed,
+                refund: account_sale.refund,
+                refunded: account_sale.refunded,
+                referee_bonus: U128(0),
+                window_amounts: vec![],
             },
             VSaleAccount::Current(account_sale) => account_sale,
         }
     }
 }
 
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum PayoutKind {
+    Purchase,
+    Refund,
+    AffiliateReward,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PayoutFailure {
+    pub account_id: AccountId,
+    pub kind: PayoutKind,
+    pub amount: U128,
+    pub timestamp: U64,
+    pub reason: String,
+    /// Payout was sent again by the owner.
+    pub retried: bool,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepositAttribution {
+    pub amount: U128,
+    pub timestamp: U64,
+    /// Referrers up the chain with the affiliate reward accrued from this deposit.
+    pub affiliate_rewards: Vec<(AccountId, U128)>,
+}
+
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct AffiliateRewardAccount {
@@ -355,17 +1169,67 @@ pub struct AffiliateRewardAccount {
     pub claimed: U128,
 }
 
-impl From<VAffiliateRewardAccount> for AffiliateRewardAccount {
-    fn from(v_account_affiliate_reward: VAffiliateRewardAccount) -> Self {
-        match v_account_affiliate_reward {
-            VAffiliateRewardAccount::Current(account_affiliate_reward) => account_affiliate_reward,
-        }
-    }
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountSaleOutput {
+    pub sale_id: u64,
+    pub amount: U128,
+    /// Distribute token available to claim now.
+    pub claimable: U128,
+    pub claimed: U128,
+    /// Deposit token available to refund now.
+    pub refundable: U128,
+    pub refunded: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReferralNodeOutput {
+    pub account_id: AccountId,
+    pub referrer_id: AccountId,
+    /// Depth under the root of the subtree.
+    pub depth: u64,
+}
+
+/// Projected outcome of the sale for the account, calculated the same way as the claims.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ClaimPreviewOutput {
+    /// Distribute token purchased by the account.
+    pub amount_to_claim: U128,
+    pub claimed: U128,
+    /// Deposit token returned to the account.
+    pub refund: U128,
+    pub refunded: U128,
+    /// Affiliate reward in the affiliate reward token.
+    pub affiliate_reward: U128,
+    pub affiliate_reward_claimed: U128,
+    /// Claims unlock at the sale end, there is no vesting.
+    pub unlock_timestamp: U64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AffiliateRewardOutput {
+    pub sale_id: u64,
+    /// Accrued reward in deposit token.
+    pub amount: U128,
+    pub claimed: U128,
+    /// Reward in affiliate reward token available to claim now.
+    pub claimable: U128,
+}
+
+impl From<VAffiliateRewardAccount> for AffiliateRewardAccount {
+    fn from(v_account_affiliate_reward: VAffiliateRewardAccount) -> Self {
+        match v_account_affiliate_reward {
+            VAffiliateRewardAccount::Current(account_affiliate_reward) => account_affiliate_reward,
+        }
+    }
 }
 
 impl Contract {
     fn get_sale_output(sale: VSale, sale_id: u64) -> SaleOutput {
-        let mut output: SaleOutput = sale.into();
+        let mut output: SaleOutput = sale.into_current(sale_id).into();
         output.sale_id = Some(sale_id);
         output
     }
@@ -380,7 +1244,11 @@ impl Contract {
         staked_amount: Balance,
         amount: Balance,
     ) -> Balance {
-        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
         assert_eq!(&sale.deposit_token_id, token_id, "ERR_WRONG_TOKEN");
         assert!(amount <= sale.limit_per_transaction, "ERR_LIMIT_PER_TX");
         assert!(
@@ -393,6 +1261,7 @@ impl Contract {
         } else {
             std::cmp::min(amount, sale.max_amount - sale.collected_amount)
         };
+        let is_new_account_sale = sale.account_sales.get(sender_id).is_none();
         let mut account_sale = sale
             .account_sales
             .get(sender_id)
@@ -403,6 +1272,8 @@ impl Contract {
                 claimed: U128(0),
                 refund: U128(0),
                 refunded: U128(0),
+                referee_bonus: U128(0),
+                window_amounts: vec![],
             });
         account_sale.amount = U128(account_sale.amount.0 + deposit_amount);
         assert!(
@@ -410,41 +1281,140 @@ impl Contract {
             "ERR_WRONG_AMOUNT"
         );
 
+        // Tokens of price changing sales are purchased at deposit time.
+        let purchase_price = match sale.sale_type {
+            SaleType::DutchAuction => Some(sale.get_dutch_auction_price(env::block_timestamp())),
+            SaleType::BondingCurve => {
+                Some(sale.get_bonding_curve_price(sale.collected_amount + deposit_amount / 2))
+            }
+            _ => None,
+        };
+        if let Some(price) = purchase_price {
+            let amount_to_claim = get_purchase_amount(
+                deposit_amount,
+                price,
+                sale.distribute_token_decimals
+                    .expect("ERR_NO_TOKEN_DECIMALS"),
+            );
+            account_sale.amount_to_claim = U128(account_sale.amount_to_claim.0 + amount_to_claim);
+            sale.sold_amount += amount_to_claim;
+            if sale.sale_type == SaleType::DutchAuction && deposit_amount > 0 {
+                sale.clearing_price = Some(price);
+            }
+        }
+
+        if let Some(index) = sale.get_bonus_window_index(env::block_timestamp()) {
+            account_sale
+                .window_amounts
+                .resize(sale.bonus_windows.len(), U128(0));
+            account_sale.window_amounts[index] =
+                U128(account_sale.window_amounts[index].0 + deposit_amount);
+            sale.bonus_window_amounts
+                .resize(sale.bonus_windows.len(), 0);
+            sale.bonus_window_amounts[index] += deposit_amount;
+        }
+
         let fees = self.referral_fees.clone();
-        if let Some(referrer_v_account_1) = self.accounts.get(sender_id) {
-            let referrer_account_1: Account = referrer_v_account_1.into();
-            let reward_1 = deposit_amount * fees[0] as u128 / REFERRAL_FEE_DENOMINATOR;
-            self.internal_insert_affiliate(&mut sale, &referrer_account_1.referrer, reward_1);
-            if let Some(referrer_v_account_2) = self.accounts.get(&referrer_account_1.referrer) {
-                let referrer_account_2: Account = referrer_v_account_2.into();
-                let reward_2 = deposit_amount * fees[1] as u128 / REFERRAL_FEE_DENOMINATOR;
-                self.internal_insert_affiliate(&mut sale, &referrer_account_2.referrer, reward_2);
-                if let Some(referrer_v_account_3) = self.accounts.get(&referrer_account_2.referrer)
-                {
-                    let referrer_account_3: Account = referrer_v_account_3.into();
-                    let reward_3 = deposit_amount * fees[2] as u128 / REFERRAL_FEE_DENOMINATOR;
-                    self.internal_insert_affiliate(
-                        &mut sale,
-                        &referrer_account_3.referrer,
-                        reward_3,
-                    );
-                }
+        let mut affiliate_rewards: Vec<(AccountId, U128)> = vec![];
+        let mut account_id = sender_id.clone();
+        for fee in fees {
+            let account: Account = if let Some(v_account) = self.accounts.get(&account_id) {
+                v_account.into()
+            } else {
+                break;
+            };
+            let reward = deposit_amount * fee as u128 / REFERRAL_FEE_DENOMINATOR;
+            if !self.internal_accrue_affiliate_reward(
+                &mut sale,
+                &account.referrer,
+                sender_id,
+                reward,
+                &mut affiliate_rewards,
+            ) {
+                break;
             }
+            account_id = account.referrer;
         }
+        let mut deposit_attributions =
+            sale.deposit_attributions.get(sender_id).unwrap_or_else(|| {
+                Vector::new(StorageKey::AccountDepositAttributions {
+                    sale_id,
+                    account_id: sender_id.clone(),
+                })
+            });
+        assert!(
+            deposit_attributions.len() < MAX_DEPOSIT_ATTRIBUTIONS,
+            "ERR_TOO_MANY_DEPOSITS"
+        );
+        deposit_attributions.push(&DepositAttribution {
+            amount: U128(deposit_amount),
+            timestamp: U64(env::block_timestamp()),
+            affiliate_rewards,
+        });
+        sale.deposit_attributions
+            .insert(sender_id, &deposit_attributions);
 
         sale.account_sales
             .insert(sender_id, &VSaleAccount::Current(account_sale));
         sale.collected_amount += deposit_amount;
+        if staked_amount > 0 {
+            sale.stats.staked_deposit_amount += deposit_amount;
+        } else {
+            sale.stats.non_staked_deposit_amount += deposit_amount;
+        }
         self.sales.insert(&sale_id, &VSale::Current(sale));
+        if is_new_account_sale {
+            self.internal_add_account_sale_id(sender_id, sale_id);
+        }
         amount - deposit_amount
     }
 
+    pub(crate) fn internal_add_account_sale_id(&mut self, account_id: &AccountId, sale_id: u64) {
+        let mut sale_ids = self.account_sale_ids.get(account_id).unwrap_or_default();
+        if !sale_ids.contains(&sale_id) {
+            sale_ids.push(sale_id);
+            self.account_sale_ids.insert(account_id, &sale_ids);
+        }
+    }
+
+    /// Accrues the reward to the referrer or, if it's the owner, as the sale owner referral policy
+    /// says. Owner is its own referrer, so the chain ends there and the policy applies only once.
+    /// Returns whether the chain goes on to the next level.
+    fn internal_accrue_affiliate_reward(
+        &mut self,
+        sale: &mut Sale,
+        referrer_id: &AccountId,
+        sender_id: &AccountId,
+        amount: Balance,
+        affiliate_rewards: &mut Vec<(AccountId, U128)>,
+    ) -> bool {
+        if referrer_id != &self.owner_id {
+            let accrued_amount = self.internal_insert_affiliate(sale, referrer_id, amount);
+            sale.update_top_affiliates(referrer_id, accrued_amount);
+            affiliate_rewards.push((referrer_id.clone(), U128(amount)));
+            return true;
+        }
+        // Policy recipients aren't affiliates, so they stay off the leaderboard.
+        let receiver_id = match &sale.owner_referral_policy {
+            OwnerReferralPolicy::Owner => Some(referrer_id.clone()),
+            OwnerReferralPolicy::Drop => None,
+            OwnerReferralPolicy::Treasury(treasury_id) => Some(treasury_id.clone()),
+            OwnerReferralPolicy::BuyerBonus => Some(sender_id.clone()),
+        };
+        if let Some(receiver_id) = receiver_id {
+            self.internal_insert_affiliate(sale, &receiver_id, amount);
+            affiliate_rewards.push((receiver_id, U128(amount)));
+        }
+        false
+    }
+
+    /// Adds the amount to the affiliate reward of the account. Returns the accrued reward.
     pub(crate) fn internal_insert_affiliate(
         &mut self,
         sale: &mut Sale,
         account_id: &AccountId,
         amount: u128,
-    ) {
+    ) -> Balance {
         let account_affiliate_reward = if let Some(v_account_affiliate_reward) =
             sale.account_affiliate_rewards.get(account_id)
         {
@@ -460,10 +1430,13 @@ impl Contract {
             }
         };
 
+        sale.stats.affiliate_rewards_accrued += amount;
+        let accrued_amount = account_affiliate_reward.amount.0;
         sale.account_affiliate_rewards.insert(
             account_id,
             &VAffiliateRewardAccount::Current(account_affiliate_reward),
         );
+        accrued_amount
     }
 
     pub(crate) fn internal_finalize_near_deposit(
@@ -527,8 +1500,141 @@ impl Contract {
         )
     }
 
+    pub fn get_affiliates_on_level(
+        &self,
+        account_id: AccountId,
+        level: u8,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<AccountId> {
+        let account: Account = self
+            .accounts
+            .get(&account_id)
+            .expect("ERR_NO_ACCOUNT")
+            .into();
+        if let Some(affiliates) = account.affiliates.get(&level) {
+            let affiliates = affiliates.as_vector();
+            (from_index..std::cmp::min(from_index + limit, affiliates.len()))
+                .map(|index| affiliates.get(index).unwrap())
+                .collect()
+        } else {
+            vec![]
+        }
+    }
+
+    /// Accounts of the referral subtree under the given account in depth-first order.
+    /// Cursor is the path of affiliate indices to the next account, returned while accounts are left.
+    /// Direct affiliates of the owner aren't stored, so the owner subtree has only the owner.
+    pub fn get_referral_subtree(
+        &self,
+        account_id: AccountId,
+        cursor: Option<Vec<u64>>,
+        limit: u64,
+    ) -> (Vec<ReferralNodeOutput>, Option<Vec<u64>>) {
+        // Empty page would return the same cursor forever.
+        assert!(
+            limit > 0 && limit <= MAX_REFERRAL_SUBTREE_LIMIT,
+            "ERR_WRONG_LIMIT"
+        );
+        let mut path = cursor.unwrap_or_default();
+        // Accounts from the root to the next account.
+        let mut path_account_ids = vec![account_id];
+        for index in path.iter() {
+            let affiliate_id = self
+                .internal_get_direct_affiliate(path_account_ids.last().unwrap(), *index)
+                .expect("ERR_WRONG_CURSOR");
+            path_account_ids.push(affiliate_id);
+        }
+
+        let mut nodes = vec![];
+        while (nodes.len() as u64) < limit {
+            let current_id = path_account_ids.last().unwrap().clone();
+            let account: Account = self
+                .accounts
+                .get(&current_id)
+                .expect("ERR_NO_ACCOUNT")
+                .into();
+            let has_affiliates = get_affiliates_num(&account, 0) > 0;
+            nodes.push(ReferralNodeOutput {
+                account_id: current_id.clone(),
+                referrer_id: account.referrer,
+                depth: path.len() as u64,
+            });
+
+            if has_affiliates {
+                path.push(0);
+                path_account_ids.push(self.internal_get_direct_affiliate(&current_id, 0).unwrap());
+                continue;
+            }
+            // Go up until an account with the next sibling.
+            loop {
+                let index = match path.pop() {
+                    Some(index) => index,
+                    None => return (nodes, None),
+                };
+                path_account_ids.pop();
+                if let Some(sibling_id) =
+                    self.internal_get_direct_affiliate(path_account_ids.last().unwrap(), index + 1)
+                {
+                    path.push(index + 1);
+                    path_account_ids.push(sibling_id);
+                    break;
+                }
+            }
+        }
+        (nodes, Some(path))
+    }
+
+    fn internal_get_direct_affiliate(
+        &self,
+        account_id: &AccountId,
+        index: u64,
+    ) -> Option<AccountId> {
+        let account: Account = self.accounts.get(account_id)?.into();
+        account.affiliates.get(&0)?.as_vector().get(index)
+    }
+
+    /// Affiliate rewards of the given account in sales from `from_index` to `from_index + limit`.
+    pub fn get_affiliate_rewards(
+        &self,
+        account_id: AccountId,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<AffiliateRewardOutput> {
+        (from_index..std::cmp::min(from_index + limit, self.num_sales))
+            .filter_map(|sale_id| {
+                let sale: Sale = self.sales.get(&sale_id)?.into_current(sale_id);
+                let account_affiliate_reward: AffiliateRewardAccount =
+                    sale.account_affiliate_rewards.get(&account_id)?.into();
+                Some(AffiliateRewardOutput {
+                    sale_id,
+                    claimable: U128(sale.get_claimable_affiliate_reward(&account_affiliate_reward)),
+                    amount: account_affiliate_reward.amount,
+                    claimed: account_affiliate_reward.claimed,
+                })
+            })
+            .collect()
+    }
+
+    /// Top affiliates of the sale by accrued reward.
+    pub fn get_affiliate_leaderboard(&self, sale_id: u64) -> Vec<(AccountId, U128)> {
+        let sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        sale.top_affiliates
+            .into_iter()
+            .map(|(account_id, amount)| (account_id, U128(amount)))
+            .collect()
+    }
+
     pub fn get_sale_amount(&self, sale_id: u64, account_id: AccountId) -> U128 {
-        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
         if let Some(sale_account) = sale.account_sales.get(&account_id) {
             let sale_account: SaleAccount = sale_account.into();
             sale_account.amount
@@ -537,52 +1643,31 @@ impl Contract {
         }
     }
 
+    /// Account was referred by other account than the owner.
+    pub(crate) fn is_referred_account(&self, account_id: &AccountId) -> bool {
+        self.accounts
+            .get(account_id)
+            .map(|v_account| {
+                let account: Account = v_account.into();
+                account.referrer != self.owner_id
+            })
+            .unwrap_or(false)
+    }
+
     fn internal_calculate_purchase(&mut self, sale_id: u64) {
-        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
         let account_id = env::predecessor_account_id();
-        let distribute_token_decimals_value = sale
-            .distribute_token_decimals
-            .expect("ERR_NO_TOKEN_DECIMALS");
         if let Some(v_sale_account) = sale.account_sales.get(&account_id) {
             let mut account_sale: SaleAccount = v_sale_account.into();
-            assert_ne!(account_sale.amount.0, 0, "ERR_NO_ALLOCATION");
-
-            let deposit_amount = account_sale.amount.0;
-
-            let amount_to_claim: u128 = if account_sale.claimed.0 == 0 {
-                if sale.collected_amount <= sale.max_amount || sale.sale_type == SaleType::ByAmount
-                {
-                    get_amount_to_claim(
-                        deposit_amount,
-                        sale.max_amount,
-                        sale.max_amount,
-                        sale.price,
-                        distribute_token_decimals_value,
-                    )
-                } else {
-                    get_amount_to_claim(
-                        deposit_amount,
-                        sale.collected_amount,
-                        sale.max_amount,
-                        sale.price,
-                        distribute_token_decimals_value,
-                    )
-                }
-            } else {
-                account_sale.claimed.0
-            };
-            if account_sale.amount_to_claim.0 == 0 && amount_to_claim > 0 {
-                account_sale.amount_to_claim = U128(amount_to_claim);
-            }
-            if sale.sale_type == SaleType::BySubscription {
-                let client_purchase_amount: u128 = (U256::from(amount_to_claim)
-                    * U256::from(sale.price)
-                    / U256::from(u128::pow(10, distribute_token_decimals_value as u32)))
-                .as_u128();
-                if account_sale.refund.0 == 0 && deposit_amount > client_purchase_amount {
-                    account_sale.refund = U128(deposit_amount - client_purchase_amount);
-                }
-            }
+            sale.calculate_account_purchase(
+                &account_id,
+                &mut account_sale,
+                self.is_referred_account(&account_id),
+            );
             sale.account_sales
                 .insert(&account_id, &VSaleAccount::Current(account_sale));
             self.sales.insert(&sale_id, &VSale::Current(sale));
@@ -591,8 +1676,22 @@ impl Contract {
         }
     }
 
-    pub fn claim_purchase(&mut self, sale_id: u64) -> Promise {
-        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+    /// Claims the purchase of the given sale.
+    /// With `storage_deposit` the attached deposit registers the caller on the distribute token first.
+    #[payable]
+    pub fn claim_purchase(&mut self, sale_id: u64, storage_deposit: Option<bool>) -> Promise {
+        let storage_deposit_amount = if storage_deposit.unwrap_or(false) {
+            assert!(env::attached_deposit() > 0, "ERR_NO_STORAGE_DEPOSIT");
+            env::attached_deposit()
+        } else {
+            assert_eq!(env::attached_deposit(), 0, "ERR_UNEXPECTED_DEPOSIT");
+            0
+        };
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
         assert!(sale.claim_available, "ERR_CLAIM_NOT_AVAILABLE");
         assert_ne!(sale.price, 0, "ERR_NO_SALE_PRICE");
         assert!(
@@ -603,8 +1702,14 @@ impl Contract {
         let distribute_token_id = sale.distribute_token_id.clone().expect("ERR_NO_TOKEN_ID");
 
         let account_id = env::predecessor_account_id();
+        sale.lock_payout(&account_id, PayoutKind::Purchase);
 
         self.internal_calculate_purchase(sale_id);
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
 
         if let Some(v_sale_account) = sale.account_sales.get(&account_id) {
             let mut account_sale: SaleAccount = v_sale_account.into();
@@ -625,18 +1730,123 @@ impl Contract {
                 .insert(&account_id, &VSaleAccount::Current(account_sale));
             self.sales.insert(&sale_id, &VSale::Current(sale));
 
-            self.withdraw_purchase(account_id, amount_to_claim.0, distribute_token_id, sale_id)
+            self.withdraw_purchase(
+                account_id,
+                amount_to_claim.0,
+                distribute_token_id,
+                sale_id,
+                storage_deposit_amount,
+            )
         } else {
             panic!("ERR_NO_DATA")
         }
     }
 
-    pub fn claim_refund(&mut self, sale_id: u64) -> Promise {
-        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+    /// Claims purchases of the given sales with one transfer per distribute token.
+    /// Sales where nothing can be claimed yet are skipped.
+    pub fn claim_purchases(&mut self, sale_ids: Vec<u64>) -> Promise {
+        assert!(sale_ids.len() <= MAX_CLAIM_SALES, "ERR_TOO_MANY_SALES");
         assert!(
-            sale.sale_type == SaleType::BySubscription,
-            "ERR_REFUND_NOT_ALLOWED"
+            env::prepaid_gas() >= Gas(GAS_FOR_CLAIM_PURCHASE.0 * sale_ids.len() as u64),
+            "ERR_NOT_ENOUGH_GAS"
         );
+        let account_id = env::predecessor_account_id();
+
+        // Distribute token, total amount and amounts per sale.
+        let mut token_purchases: TokenPayouts = vec![];
+        for sale_id in sale_ids {
+            let mut sale: Sale = self
+                .sales
+                .get(&sale_id)
+                .expect("ERR_NO_SALE")
+                .into_current(sale_id);
+            if !sale.claim_available
+                || sale.price == 0
+                || env::block_timestamp() <= sale.end_date
+                || sale.distribute_token_id.is_none()
+                || sale.distribute_token_decimals.is_none()
+                || (sale.sale_type == SaleType::Lottery && sale.lottery_winners.is_none())
+            {
+                continue;
+            }
+            let mut account_sale: SaleAccount = match sale.account_sales.get(&account_id) {
+                Some(v_sale_account) => v_sale_account.into(),
+                None => continue,
+            };
+            if account_sale.amount.0 == 0
+                || account_sale.claimed.0 != 0
+                || sale.is_payout_in_flight(&account_id, PayoutKind::Purchase)
+            {
+                continue;
+            }
+            sale.calculate_account_purchase(
+                &account_id,
+                &mut account_sale,
+                self.is_referred_account(&account_id),
+            );
+            let amount_to_claim = account_sale.amount_to_claim;
+            if amount_to_claim.0 == 0 {
+                continue;
+            }
+            account_sale.claimed = amount_to_claim;
+            sale.claim_begun = true;
+            sale.lock_payout(&account_id, PayoutKind::Purchase);
+            let distribute_token_id = sale.distribute_token_id.clone().unwrap();
+            sale.account_sales
+                .insert(&account_id, &VSaleAccount::Current(account_sale));
+            self.sales.insert(&sale_id, &VSale::Current(sale));
+
+            if let Some(token_purchase) = token_purchases
+                .iter_mut()
+                .find(|(token_id, _, _)| token_id == &distribute_token_id)
+            {
+                token_purchase.1 += amount_to_claim.0;
+                token_purchase.2.push((sale_id, amount_to_claim));
+            } else {
+                token_purchases.push((
+                    distribute_token_id,
+                    amount_to_claim.0,
+                    vec![(sale_id, amount_to_claim)],
+                ));
+            }
+        }
+        assert!(!token_purchases.is_empty(), "ERR_NOTHING_TO_CLAIM");
+
+        let mut transfers: Option<Promise> = None;
+        for (distribute_token_id, amount, sale_amounts) in token_purchases {
+            log!("Amount to claim: {} of {}", amount, distribute_token_id);
+            let gas_for_after_transfer =
+                Gas(GAS_FOR_AFTER_FT_TRANSFER.0 * sale_amounts.len() as u64);
+            let transfer = ext_fungible_token::ft_transfer(
+                account_id.clone(),
+                amount.into(),
+                Some(format!("Claim {} of {}", amount, distribute_token_id)),
+                distribute_token_id,
+                ONE_YOCTO,
+                GAS_FOR_FT_TRANSFER,
+            )
+            .then(ext_self::after_withdraw_purchases(
+                account_id.clone(),
+                sale_amounts,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                gas_for_after_transfer,
+            ));
+            transfers = Some(match transfers {
+                Some(promise) => promise.and(transfer),
+                None => transfer,
+            });
+        }
+        transfers.unwrap()
+    }
+
+    pub fn claim_refund(&mut self, sale_id: u64) -> Promise {
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        assert!(sale.sale_type.is_refundable(), "ERR_REFUND_NOT_ALLOWED");
         assert!(sale.refund_available, "ERR_REFUND_NOT_AVAILABLE");
         assert!(
             env::block_timestamp() > sale.end_date,
@@ -644,8 +1854,14 @@ impl Contract {
         );
 
         let account_id = env::predecessor_account_id();
+        sale.lock_payout(&account_id, PayoutKind::Refund);
 
         self.internal_calculate_purchase(sale_id);
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
 
         if let Some(v_sale_account) = sale.account_sales.get(&account_id) {
             let mut account_sale: SaleAccount = v_sale_account.into();
@@ -670,13 +1886,17 @@ impl Contract {
     }
 
     pub fn claim_affiliate_reward(&mut self, sale_id: u64) -> Promise {
-        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
-        let distribute_token_decimals_value = sale
-            .distribute_token_decimals
-            .expect("ERR_NO_TOKEN_DECIMALS");
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
         let account_id = env::predecessor_account_id();
 
-        assert!(sale.refund_available, "ERR_NOT_AVAILABLE");
+        assert!(
+            sale.affiliate_reward_available,
+            "ERR_AFFILIATE_REWARD_NOT_AVAILABLE"
+        );
         assert!(
             env::block_timestamp() > sale.end_date,
             "ERR_SALE_IN_PROGRESS"
@@ -688,34 +1908,15 @@ impl Contract {
             assert_ne!(account_affiliate_reward.amount.0, 0, "ERR_NOTHING_TO_CLAIM");
             assert_eq!(account_affiliate_reward.claimed.0, 0, "ERR_ALREADY_CLAIMED");
 
-            let deposit_amount = account_affiliate_reward.amount.0;
-
-            let amount_to_claim: u128 = if sale.collected_amount <= sale.max_amount
-                || sale.sale_type == SaleType::ByAmount
-            {
-                get_amount_to_claim(
-                    deposit_amount,
-                    sale.max_amount,
-                    sale.collected_amount,
-                    sale.price,
-                    distribute_token_decimals_value,
-                )
-            } else {
-                get_amount_to_claim(
-                    deposit_amount,
-                    sale.collected_amount,
-                    sale.collected_amount,
-                    sale.price,
-                    distribute_token_decimals_value,
-                )
-            };
+            let amount_to_claim = sale.get_affiliate_reward(account_affiliate_reward.amount.0);
 
             assert_ne!(amount_to_claim, 0, "ERR_NOTHING_TO_CLAIM");
             log!("Amount to claim: {}", amount_to_claim);
 
             account_affiliate_reward.claimed = U128(amount_to_claim);
+            sale.lock_payout(&account_id, PayoutKind::AffiliateReward);
 
-            let deposit_token_id = sale.deposit_token_id.clone();
+            let token_account_id = sale.get_affiliate_reward_token_id();
 
             sale.account_affiliate_rewards.insert(
                 &account_id,
@@ -723,7 +1924,7 @@ impl Contract {
             );
             self.sales.insert(&sale_id, &VSale::Current(sale));
 
-            self.withdraw_affiliate_reward(account_id, amount_to_claim, deposit_token_id, sale_id)
+            self.withdraw_affiliate_reward(account_id, amount_to_claim, token_account_id, sale_id)
         } else {
             panic!("ERR_NO_DATA");
         }
@@ -770,8 +1971,93 @@ impl Contract {
             "WRONG_DECIMALS"
         );
 
-        self.sales
-            .insert(&self.num_sales, &VSale::new(self.num_sales, sale));
+        // Deposits without explicit staking contracts query all of them.
+        assert!(
+            sale.staking_contracts.len() <= MAX_STAKING_CONTRACTS_PER_DEPOSIT,
+            "ERR_TOO_MANY_STAKING_CONTRACTS"
+        );
+
+        if sale.sale_type == SaleType::Lottery {
+            assert!(
+                !sale.hard_max_amount_limit,
+                "ERR_LOTTERY_HARD_MAX_AMOUNT_LIMIT"
+            );
+            assert!(
+                sale.max_buy.0 > 0 && sale.min_buy.0 == sale.max_buy.0,
+                "ERR_LOTTERY_MUST_HAVE_FIXED_ALLOCATION"
+            );
+            assert!(
+                sale.max_amount.0 >= sale.max_buy.0,
+                "ERR_LOTTERY_MUST_HAVE_MAX_AMOUNT"
+            );
+            assert!(
+                sale.max_amount.0 / sale.max_buy.0 <= MAX_LOTTERY_WINNERS,
+                "ERR_LOTTERY_TOO_MANY_WINNERS"
+            );
+        }
+
+        if sale.sale_type == SaleType::DutchAuction {
+            let floor_price = sale.floor_price.expect("ERR_NO_FLOOR_PRICE").0;
+            assert!(
+                floor_price > 0 && floor_price <= sale.price.0,
+                "ERR_WRONG_FLOOR_PRICE"
+            );
+            assert!(sale.end_date.0 > sale.start_date.0, "ERR_WRONG_DATES");
+            assert!(
+                sale.hard_max_amount_limit,
+                "ERR_DUTCH_AUCTION_MUST_HAVE_HARD_MAX_AMOUNT_LIMIT"
+            );
+        }
+
+        if let Some(bonus_windows) = sale.bonus_windows.as_ref() {
+            assert!(
+                sale.sale_type == SaleType::ByAmount,
+                "ERR_BONUS_WINDOWS_NOT_ALLOWED"
+            );
+            let mut previous_end_date = sale.start_date.0;
+            for bonus_window in bonus_windows {
+                assert!(
+                    bonus_window.end_date.0 > previous_end_date
+                        && bonus_window.end_date.0 <= sale.end_date.0
+                        && bonus_window.bonus > 0,
+                    "ERR_WRONG_BONUS_WINDOW"
+                );
+                previous_end_date = bonus_window.end_date.0;
+            }
+        }
+
+        if sale.referee_bonus.unwrap_or(0) > 0 {
+            assert!(
+                sale.referee_bonus_supply
+                    .map(|referee_bonus_supply| referee_bonus_supply.0)
+                    .unwrap_or(0)
+                    > 0,
+                "ERR_NO_REFEREE_BONUS_SUPPLY"
+            );
+        }
+
+        if sale.sale_type == SaleType::BondingCurve {
+            let bonding_curve = sale.bonding_curve.as_ref().expect("ERR_NO_BONDING_CURVE");
+            let step = match bonding_curve {
+                BondingCurve::Linear { step, .. } => step.0,
+                BondingCurve::Exponential { step, .. } => step.0,
+            };
+            assert!(step > 0, "ERR_WRONG_BONDING_CURVE");
+            // Collected amount is bounded by max_amount, so the price is checked at its top.
+            assert!(
+                sale.hard_max_amount_limit,
+                "ERR_BONDING_CURVE_MUST_HAVE_HARD_MAX_AMOUNT_LIMIT"
+            );
+            assert!(
+                get_bonding_curve_price(bonding_curve, sale.price.0, sale.max_amount.0).is_some(),
+                "ERR_BONDING_CURVE_OVERFLOW"
+            );
+        }
+
+        self.sales.insert(
+            &self.num_sales,
+            &VSale::new(self.num_sales, sale, self.owner_referral_policy.clone()),
+        );
         let sale_id = self.num_sales;
         self.num_sales += 1;
         sale_id
@@ -779,7 +2065,11 @@ impl Contract {
 
     #[private]
     pub fn remove_sale(&mut self, sale_id: u64) {
-        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
         assert_eq!(sale.collected_amount, 0, "SALE_NOT_EMPTY");
         self.sales.remove(&sale_id);
     }
@@ -790,9 +2080,19 @@ impl Contract {
         self.referral_fees = referral_fees;
     }
 
+    /// Applies to the sales created after the update.
+    #[private]
+    pub fn update_owner_referral_policy(&mut self, owner_referral_policy: OwnerReferralPolicy) {
+        self.owner_referral_policy = owner_referral_policy;
+    }
+
     #[private]
     pub fn update_sale_dates(&mut self, sale_id: u64, start_date: U64, end_date: U64) {
-        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
         assert!(sale.collected_amount < sale.max_amount, "ERR_SALE_DONE");
         sale.start_date = start_date.into();
         sale.end_date = end_date.into();
@@ -805,7 +2105,11 @@ impl Contract {
         sale_id: u64,
         distribute_token_id: AccountId,
     ) {
-        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
         assert!(sale.distribute_token_id.is_none(), "ERR_ALREADY_SET");
         sale.distribute_token_id = Some(distribute_token_id);
         self.sales.insert(&sale_id, &VSale::Current(sale));
@@ -813,13 +2117,27 @@ impl Contract {
 
     #[private]
     pub fn update_sale_price(&mut self, sale_id: u64, price: U128) {
-        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
         assert!(!sale.claim_begun, "ERR_CLAIM_ALREADY_BEGUN");
+        assert!(!sale.is_finalization_begun(), "ERR_SALE_FINALIZATION_BEGUN");
         let timestamp = env::block_timestamp();
         assert!(
             timestamp < sale.start_date || timestamp > sale.end_date,
             "ERR_SALE_IS_ACTIVE"
         );
+        if let Some(floor_price) = sale.floor_price {
+            assert!(price.0 >= floor_price, "ERR_WRONG_FLOOR_PRICE");
+        }
+        if let Some(bonding_curve) = sale.bonding_curve.as_ref() {
+            assert!(
+                get_bonding_curve_price(bonding_curve, price.0, sale.max_amount).is_some(),
+                "ERR_BONDING_CURVE_OVERFLOW"
+            );
+        }
         sale.price = price.0;
         self.sales.insert(&sale_id, &VSale::Current(sale));
     }
@@ -830,15 +2148,24 @@ impl Contract {
         sale_id: u64,
         distribute_token_decimals: u8,
     ) {
-        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
         assert!(sale.distribute_token_decimals.is_none(), "ERR_ALREADY_SET");
+        assert!(!sale.is_finalization_begun(), "ERR_SALE_FINALIZATION_BEGUN");
         sale.distribute_token_decimals = Some(distribute_token_decimals);
         self.sales.insert(&sale_id, &VSale::Current(sale));
     }
 
     #[private]
     pub fn update_sale_claim_available(&mut self, sale_id: u64, claim_available: bool) {
-        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
         assert!(sale.distribute_token_id.is_some(), "ERR_NO_TOKEN_ID");
         assert!(
             sale.distribute_token_decimals.is_some(),
@@ -850,7 +2177,11 @@ impl Contract {
 
     #[private]
     pub fn update_sale_refund_available(&mut self, sale_id: u64, refund_available: bool) {
-        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
         assert!(sale.distribute_token_id.is_some(), "ERR_NO_TOKEN_ID");
         assert!(
             sale.distribute_token_decimals.is_some(),
@@ -860,59 +2191,750 @@ impl Contract {
         self.sales.insert(&sale_id, &VSale::Current(sale));
     }
 
-    pub fn get_num_sales(&self) -> u64 {
-        self.num_sales
-    }
-
-    pub fn get_sale(&self, sale_id: u64) -> SaleOutput {
-        Contract::get_sale_output(self.sales.get(&sale_id).expect("ERR_NO_SALE"), sale_id)
+    #[private]
+    pub fn update_sale_affiliate_reward_available(
+        &mut self,
+        sale_id: u64,
+        affiliate_reward_available: bool,
+    ) {
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        if sale.affiliate_reward_token == AffiliateRewardToken::DistributeToken {
+            assert!(sale.distribute_token_id.is_some(), "ERR_NO_TOKEN_ID");
+            assert!(
+                sale.distribute_token_decimals.is_some(),
+                "ERR_NO_TOKEN_DECIMALS"
+            );
+        }
+        sale.affiliate_reward_available = affiliate_reward_available;
+        self.sales.insert(&sale_id, &VSale::Current(sale));
     }
 
-    pub fn get_sales(&self, from_index: u64, limit: u64) -> Vec<SaleOutput> {
-        (from_index..std::cmp::min(from_index + limit, self.num_sales))
-            .filter_map(|sale_id| {
-                self.sales
-                    .get(&sale_id)
-                    .map(|sale| Contract::get_sale_output(sale, sale_id))
-            })
-            .collect()
+    /// Adds a batch of staked amounts to the sale staking snapshot.
+    #[private]
+    pub fn update_sale_staking_snapshot(
+        &mut self,
+        sale_id: u64,
+        staked_amounts: Vec<(AccountId, U128)>,
+    ) {
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        assert!(env::block_timestamp() < sale.start_date, "ERR_SALE_STARTED");
+        assert!(
+            sale.staking_snapshot_root.is_none(),
+            "ERR_HAS_STAKING_SNAPSHOT_ROOT"
+        );
+        let mut staking_snapshot = sale
+            .staking_snapshot
+            .take()
+            .unwrap_or_else(|| LookupMap::new(StorageKey::StakingSnapshot { sale_id }));
+        for (account_id, staked_amount) in staked_amounts.iter() {
+            staking_snapshot.insert(account_id, &staked_amount.0);
+        }
+        sale.staking_snapshot = Some(staking_snapshot);
+        self.sales.insert(&sale_id, &VSale::Current(sale));
     }
 
-    pub fn get_sale_accounts(
-        &self,
+    /// Sets merkle root of the sale staking snapshot.
+    /// Leaf is sha256(account_id ++ staked_amount as 16 little endian bytes), pairs are hashed sorted.
+    #[private]
+    pub fn update_sale_staking_snapshot_root(
+        &mut self,
         sale_id: u64,
-        from_index: u64,
-        limit: u64,
-    ) -> Vec<(AccountId, SaleAccount)> {
-        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
-        let keys = sale.account_sales.keys_as_vector();
-        let values = sale.account_sales.values_as_vector();
-        (from_index..std::cmp::min(from_index + limit, keys.len()))
-            .map(|index| (keys.get(index).unwrap(), values.get(index).unwrap().into()))
-            .collect()
+        staking_snapshot_root: CryptoHash,
+    ) {
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        assert!(env::block_timestamp() < sale.start_date, "ERR_SALE_STARTED");
+        assert!(sale.staking_snapshot.is_none(), "ERR_HAS_STAKING_SNAPSHOT");
+        sale.staking_snapshot_root = Some(staking_snapshot_root);
+        self.sales.insert(&sale_id, &VSale::Current(sale));
     }
 
-    pub fn get_sale_account(&self, sale_id: u64, account_id: AccountId) -> SaleAccount {
-        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
-        if let Some(sale_account) = sale.account_sales.get(&account_id) {
-            sale_account.into()
-        } else {
+    /// Sends purchased tokens to the sale accounts that haven't claimed them yet.
+    /// Returns number of transfers sent.
+    #[private]
+    pub fn distribute_sale(&mut self, sale_id: u64, from_index: u64, limit: u64) -> u64 {
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        assert!(sale.claim_available, "ERR_CLAIM_NOT_AVAILABLE");
+        assert_ne!(sale.price, 0, "ERR_NO_SALE_PRICE");
+        assert!(
+            env::block_timestamp() > sale.end_date,
+            "ERR_SALE_IN_PROGRESS"
+        );
+        assert!(limit <= MAX_DISTRIBUTE_LIMIT, "ERR_LIMIT_TOO_BIG");
+        let distribute_token_id = sale.distribute_token_id.clone().expect("ERR_NO_TOKEN_ID");
+
+        let keys = sale.account_sales.keys_as_vector();
+        let account_ids: Vec<AccountId> = (from_index
+            ..std::cmp::min(from_index + limit, keys.len()))
+            .map(|index| keys.get(index).unwrap())
+            .collect();
+        let mut account_amounts: Vec<(AccountId, Balance)> = vec![];
+        for account_id in account_ids {
+            let mut account_sale: SaleAccount = sale.account_sales.get(&account_id).unwrap().into();
+            if account_sale.amount.0 == 0
+                || account_sale.claimed.0 != 0
+                || sale.is_payout_in_flight(&account_id, PayoutKind::Purchase)
+            {
+                continue;
+            }
+            sale.calculate_account_purchase(
+                &account_id,
+                &mut account_sale,
+                self.is_referred_account(&account_id),
+            );
+            let amount_to_claim = account_sale.amount_to_claim.0;
+            if amount_to_claim > 0 {
+                account_sale.claimed = U128(amount_to_claim);
+                sale.lock_payout(&account_id, PayoutKind::Purchase);
+                account_amounts.push((account_id.clone(), amount_to_claim));
+            }
+            sale.account_sales
+                .insert(&account_id, &VSaleAccount::Current(account_sale));
+        }
+        if !account_amounts.is_empty() {
+            sale.claim_begun = true;
+        }
+        self.sales.insert(&sale_id, &VSale::Current(sale));
+
+        let num_transfers = account_amounts.len() as u64;
+        for (account_id, amount_to_claim) in account_amounts {
+            ext_fungible_token::ft_transfer(
+                account_id.clone(),
+                amount_to_claim.into(),
+                Some(format!(
+                    "Distribute {} of {}. Sale #{}",
+                    amount_to_claim, distribute_token_id, sale_id
+                )),
+                distribute_token_id.clone(),
+                ONE_YOCTO,
+                GAS_FOR_FT_TRANSFER,
+            )
+            .then(ext_self::after_distribute_purchase(
+                account_id,
+                amount_to_claim.into(),
+                sale_id,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_AFTER_FT_TRANSFER,
+            ));
+        }
+        log!("Distribution transfers sent: {}", num_transfers);
+        num_transfers
+    }
+
+    /// Sends again the payout of the logged failure if the account hasn't received it since.
+    #[private]
+    pub fn retry_payout_failure(&mut self, sale_id: u64, index: u64) -> Promise {
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        let mut payout_failure = sale
+            .payout_failures
+            .get(index)
+            .expect("ERR_NO_PAYOUT_FAILURE");
+        assert!(!payout_failure.retried, "ERR_ALREADY_RETRIED");
+        payout_failure.retried = true;
+        sale.payout_failures.replace(index, &payout_failure);
+
+        let account_id = payout_failure.account_id;
+        let amount = payout_failure.amount;
+        sale.lock_payout(&account_id, payout_failure.kind);
+        match payout_failure.kind {
+            PayoutKind::Purchase => {
+                let mut account_sale: SaleAccount = sale
+                    .account_sales
+                    .get(&account_id)
+                    .expect("ERR_NO_DATA")
+                    .into();
+                assert_eq!(account_sale.claimed.0, 0, "ERR_ALREADY_CLAIMED");
+                account_sale.claimed = amount;
+                let distribute_token_id =
+                    sale.distribute_token_id.clone().expect("ERR_NO_TOKEN_ID");
+                sale.account_sales
+                    .insert(&account_id, &VSaleAccount::Current(account_sale));
+                self.sales.insert(&sale_id, &VSale::Current(sale));
+                self.withdraw_purchase(account_id, amount.0, distribute_token_id, sale_id, 0)
+            }
+            PayoutKind::Refund => {
+                let mut account_sale: SaleAccount = sale
+                    .account_sales
+                    .get(&account_id)
+                    .expect("ERR_NO_DATA")
+                    .into();
+                assert_eq!(account_sale.refunded.0, 0, "ERR_ALREADY_REFUNDED");
+                account_sale.refunded = amount;
+                let token_account_id = sale.deposit_token_id.clone();
+                sale.account_sales
+                    .insert(&account_id, &VSaleAccount::Current(account_sale));
+                self.sales.insert(&sale_id, &VSale::Current(sale));
+                self.refund_purchase(account_id, amount.0, token_account_id, sale_id)
+            }
+            PayoutKind::AffiliateReward => {
+                let mut account_affiliate_reward: AffiliateRewardAccount = sale
+                    .account_affiliate_rewards
+                    .get(&account_id)
+                    .expect("ERR_NO_DATA")
+                    .into();
+                assert_eq!(account_affiliate_reward.claimed.0, 0, "ERR_ALREADY_CLAIMED");
+                account_affiliate_reward.claimed = amount;
+                let token_account_id = sale.get_affiliate_reward_token_id();
+                sale.account_affiliate_rewards.insert(
+                    &account_id,
+                    &VAffiliateRewardAccount::Current(account_affiliate_reward),
+                );
+                self.sales.insert(&sale_id, &VSale::Current(sale));
+                self.withdraw_affiliate_reward(account_id, amount.0, token_account_id, sale_id)
+            }
+        }
+    }
+
+    /// Clears the payout lock left by a transfer whose callback never completed.
+    /// Lock is kept until it expires, so a callback still in flight can't meet an unlocked payout.
+    #[private]
+    pub fn unlock_payout(&mut self, sale_id: u64, account_id: AccountId, kind: PayoutKind) {
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        let key = (account_id, kind);
+        let locked_at = sale
+            .payouts_in_flight
+            .get(&key)
+            .expect("ERR_PAYOUT_NOT_IN_PROGRESS");
+        assert!(
+            env::block_timestamp() >= locked_at + PAYOUT_LOCK_TIMEOUT,
+            "ERR_PAYOUT_LOCK_NOT_EXPIRED"
+        );
+        sale.payouts_in_flight.remove(&key);
+    }
+
+    /// Fixes allocation and refund of the sale accounts in batches once the sale is over.
+    /// Sale becomes finalized when all accounts are processed.
+    #[private]
+    pub fn finalize_sale(&mut self, sale_id: u64, from_index: u64, limit: u64) {
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        assert!(
+            env::block_timestamp() > sale.end_date,
+            "ERR_SALE_IN_PROGRESS"
+        );
+        assert!(!sale.finalized, "ERR_SALE_FINALIZED");
+        assert!(
+            from_index <= sale.num_finalized_accounts,
+            "ERR_WRONG_FROM_INDEX"
+        );
+
+        let keys = sale.account_sales.keys_as_vector();
+        let to_index = std::cmp::min(from_index + limit, keys.len());
+        let account_ids: Vec<AccountId> = (from_index..to_index)
+            .map(|index| keys.get(index).unwrap())
+            .collect();
+        for (index, account_id) in (from_index..to_index).zip(account_ids) {
+            let mut account_sale: SaleAccount = sale.account_sales.get(&account_id).unwrap().into();
+            if account_sale.amount.0 > 0 {
+                sale.calculate_account_purchase(
+                    &account_id,
+                    &mut account_sale,
+                    self.is_referred_account(&account_id),
+                );
+                // Accounts processed by previous batches are already counted.
+                if index >= sale.num_finalized_accounts {
+                    // Referee bonus is paid from its own supply.
+                    sale.allocated_amount +=
+                        account_sale.amount_to_claim.0 - account_sale.referee_bonus.0;
+                }
+                sale.account_sales
+                    .insert(&account_id, &VSaleAccount::Current(account_sale));
+            }
+        }
+
+        sale.num_finalized_accounts = std::cmp::max(sale.num_finalized_accounts, to_index);
+        if sale.num_finalized_accounts == sale.account_sales.len() {
+            let distributable_supply = sale.get_distributable_supply();
+            assert!(
+                sale.allocated_amount <= distributable_supply,
+                "ERR_ALLOCATION_EXCEEDS_SUPPLY"
+            );
+            sale.dust_amount = distributable_supply - sale.allocated_amount;
+            if sale.sale_type == SaleType::DutchAuction {
+                // Undersubscribed auction clears at the floor, not at the price of the last deposit.
+                sale.clearing_price = Some(sale.get_clearing_price());
+            }
+            sale.finalized = true;
+            log!(
+                "Sale #{} finalized. Dust amount: {}",
+                sale_id,
+                sale.dust_amount
+            );
+        } else {
+            log!(
+                "Pending accounts: {}",
+                sale.account_sales.len() - sale.num_finalized_accounts
+            );
+        }
+        self.sales.insert(&sale_id, &VSale::Current(sale));
+    }
+
+    /// Sends distribute token left after rounding down the allocations to the owner.
+    #[private]
+    pub fn claim_sale_dust(&mut self, sale_id: u64) -> Promise {
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        assert!(sale.finalized, "ERR_SALE_NOT_FINALIZED");
+        assert!(!sale.dust_claimed, "ERR_ALREADY_CLAIMED");
+        assert_ne!(sale.dust_amount, 0, "ERR_NOTHING_TO_CLAIM");
+        let distribute_token_id = sale.distribute_token_id.clone().expect("ERR_NO_TOKEN_ID");
+        let dust_amount = sale.dust_amount;
+        sale.dust_claimed = true;
+        self.sales.insert(&sale_id, &VSale::Current(sale));
+
+        ext_fungible_token::ft_transfer(
+            self.owner_id.clone(),
+            dust_amount.into(),
+            Some(format!(
+                "Claim dust {} of {}. Sale #{}",
+                dust_amount, distribute_token_id, sale_id
+            )),
+            distribute_token_id,
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::after_claim_sale_dust(
+            sale_id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_AFTER_FT_TRANSFER,
+        ))
+    }
+
+    #[private]
+    pub fn after_claim_sale_dust(&mut self, sale_id: u64) -> bool {
+        let promise_success = is_promise_success();
+        if !promise_success {
+            let mut sale: Sale = self
+                .sales
+                .get(&sale_id)
+                .expect("ERR_NO_SALE")
+                .into_current(sale_id);
+            sale.dust_claimed = false;
+            self.sales.insert(&sale_id, &VSale::Current(sale));
+            log!("Dust claim for sale #{} failed", sale_id);
+        }
+        promise_success
+    }
+
+    /// Draws winners of the lottery sale from the random seed once the sale is over.
+    #[private]
+    pub fn draw_lottery(&mut self, sale_id: u64) {
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        assert!(sale.sale_type == SaleType::Lottery, "ERR_NOT_LOTTERY");
+        assert!(
+            env::block_timestamp() > sale.end_date,
+            "ERR_SALE_IN_PROGRESS"
+        );
+        assert!(sale.lottery_winners.is_none(), "ERR_LOTTERY_ALREADY_DRAWN");
+
+        let participants = sale.account_sales.keys_as_vector();
+        let num_participants = participants.len();
+        let num_winners =
+            std::cmp::min(sale.max_amount / sale.max_buy, num_participants as u128) as u64;
+        let seed = env::random_seed();
+        let mut lottery_winners = UnorderedSet::new(StorageKey::LotteryWinners { sale_id });
+        // Partial Fisher-Yates shuffle, only swapped positions are kept in memory.
+        let mut swapped: HashMap<u64, u64> = HashMap::new();
+        for index in 0..num_winners {
+            let random_index = index + get_random_number(&seed, index) % (num_participants - index);
+            let winner_index = *swapped.get(&random_index).unwrap_or(&random_index);
+            swapped.insert(random_index, *swapped.get(&index).unwrap_or(&index));
+            lottery_winners.insert(&participants.get(winner_index).unwrap());
+        }
+        log!(
+            "Lottery winners drawn: {} of {}",
+            num_winners,
+            num_participants
+        );
+
+        sale.lottery_winners = Some(lottery_winners);
+        self.sales.insert(&sale_id, &VSale::Current(sale));
+    }
+
+    pub fn get_num_sales(&self) -> u64 {
+        self.num_sales
+    }
+
+    pub fn get_sale(&self, sale_id: u64) -> SaleOutput {
+        Contract::get_sale_output(self.sales.get(&sale_id).expect("ERR_NO_SALE"), sale_id)
+    }
+
+    pub fn get_sales(&self, from_index: u64, limit: u64) -> Vec<SaleOutput> {
+        (from_index..std::cmp::min(from_index + limit, self.num_sales))
+            .filter_map(|sale_id| {
+                self.sales
+                    .get(&sale_id)
+                    .map(|sale| Contract::get_sale_output(sale, sale_id))
+            })
+            .collect()
+    }
+
+    /// Summaries of the sales with ids in from_index..from_index + limit that match the filter.
+    /// Continue with from_index + limit until num_sales to list all matching sales.
+    pub fn get_sale_summaries(
+        &self,
+        from_index: u64,
+        limit: u64,
+        filter: Option<SaleFilter>,
+    ) -> Vec<SaleSummaryOutput> {
+        let timestamp = env::block_timestamp();
+        (from_index..std::cmp::min(from_index + limit, self.num_sales))
+            .filter_map(|sale_id| {
+                let sale: Sale = self.sales.get(&sale_id)?.into_current(sale_id);
+                match &filter {
+                    Some(filter) if !sale.matches_filter(filter, timestamp) => None,
+                    _ => Some(sale.get_summary(sale_id)),
+                }
+            })
+            .collect()
+    }
+
+    /// Deposits of the account in the sales it joined, from `from_index` to `from_index + limit`
+    /// in the order of joining.
+    pub fn get_account_sales(
+        &self,
+        account_id: AccountId,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<AccountSaleOutput> {
+        let timestamp = env::block_timestamp();
+        self.account_sale_ids
+            .get(&account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .filter_map(|sale_id| {
+                let mut sale: Sale = self.sales.get(&sale_id)?.into_current(sale_id);
+                let mut account_sale: SaleAccount = sale.account_sales.get(&account_id)?.into();
+                let mut claimable = 0;
+                let mut refundable = 0;
+                if timestamp > sale.end_date && sale.is_purchase_calculable() {
+                    sale.calculate_account_purchase(
+                        &account_id,
+                        &mut account_sale,
+                        self.is_referred_account(&account_id),
+                    );
+                    if sale.claim_available && account_sale.claimed.0 == 0 {
+                        claimable = account_sale.amount_to_claim.0;
+                    }
+                    if sale.refund_available && account_sale.refunded.0 == 0 {
+                        refundable = account_sale.refund.0;
+                    }
+                }
+                Some(AccountSaleOutput {
+                    sale_id,
+                    amount: account_sale.amount,
+                    claimable: U128(claimable),
+                    claimed: account_sale.claimed,
+                    refundable: U128(refundable),
+                    refunded: account_sale.refunded,
+                })
+            })
+            .collect()
+    }
+
+    /// Projected purchase, refund and affiliate reward of the account without changing state.
+    pub fn preview_claim(&self, sale_id: u64, account_id: AccountId) -> ClaimPreviewOutput {
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        let mut account_sale = sale
+            .account_sales
+            .get(&account_id)
+            .map(|account_sale| account_sale.into())
+            .unwrap_or_else(|| SaleAccount {
+                amount: U128(0),
+                amount_to_claim: U128(0),
+                claimed: U128(0),
+                refund: U128(0),
+                refunded: U128(0),
+                referee_bonus: U128(0),
+                window_amounts: vec![],
+            });
+        if account_sale.amount.0 > 0 && sale.is_purchase_calculable() {
+            sale.calculate_account_purchase(
+                &account_id,
+                &mut account_sale,
+                self.is_referred_account(&account_id),
+            );
+        }
+        let (affiliate_reward, affiliate_reward_claimed) =
+            match sale.account_affiliate_rewards.get(&account_id) {
+                Some(v_account_affiliate_reward) => {
+                    let account_affiliate_reward: AffiliateRewardAccount =
+                        v_account_affiliate_reward.into();
+                    let affiliate_reward = if account_affiliate_reward.claimed.0 == 0
+                        && (sale.sale_type != SaleType::Lottery || sale.lottery_winners.is_some())
+                    {
+                        sale.get_affiliate_reward(account_affiliate_reward.amount.0)
+                    } else {
+                        0
+                    };
+                    (affiliate_reward, account_affiliate_reward.claimed.0)
+                }
+                None => (0, 0),
+            };
+        ClaimPreviewOutput {
+            amount_to_claim: account_sale.amount_to_claim,
+            claimed: account_sale.claimed,
+            refund: account_sale.refund,
+            refunded: account_sale.refunded,
+            affiliate_reward: U128(affiliate_reward),
+            affiliate_reward_claimed: U128(affiliate_reward_claimed),
+            unlock_timestamp: U64(sale.end_date),
+        }
+    }
+
+    /// Deposits and payouts of the sales created before the stats were added aren't counted.
+    pub fn get_sale_stats(&self, sale_id: u64) -> SaleStatsOutput {
+        let sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        let oversubscription_ratio = if sale.max_amount > 0 {
+            std::cmp::min(
+                U256::from(sale.collected_amount) * U256::from(10000) / U256::from(sale.max_amount),
+                U256::from(u64::MAX),
+            )
+            .as_u64()
+        } else {
+            0
+        };
+        SaleStatsOutput {
+            affiliate_rewards_accrued: U128(sale.stats.affiliate_rewards_accrued),
+            affiliate_rewards_claimed: U128(sale.stats.affiliate_rewards_claimed),
+            claimed_amount: U128(sale.stats.claimed_amount),
+            num_claimers: sale.stats.num_claimers,
+            refunded_amount: U128(sale.stats.refunded_amount),
+            oversubscription_ratio,
+            staked_deposit_amount: U128(sale.stats.staked_deposit_amount),
+            non_staked_deposit_amount: U128(sale.stats.non_staked_deposit_amount),
+        }
+    }
+
+    /// Current price of the sale, changes for dutch auctions and bonding curves.
+    pub fn get_sale_price(&self, sale_id: u64) -> U128 {
+        let sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        match sale.sale_type {
+            SaleType::DutchAuction => U128(sale.get_dutch_auction_price(env::block_timestamp())),
+            SaleType::BondingCurve => U128(sale.get_bonding_curve_price(sale.collected_amount)),
+            _ => U128(sale.price),
+        }
+    }
+
+    pub fn get_sale_accounts(
+        &self,
+        sale_id: u64,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<(AccountId, SaleAccount)> {
+        let sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        let keys = sale.account_sales.keys_as_vector();
+        let values = sale.account_sales.values_as_vector();
+        (from_index..std::cmp::min(from_index + limit, keys.len()))
+            .map(|index| (keys.get(index).unwrap(), values.get(index).unwrap().into()))
+            .collect()
+    }
+
+    pub fn get_sale_account(&self, sale_id: u64, account_id: AccountId) -> SaleAccount {
+        let sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        if let Some(sale_account) = sale.account_sales.get(&account_id) {
+            sale_account.into()
+        } else {
             SaleAccount {
                 amount: U128(0),
                 amount_to_claim: U128(0),
                 claimed: U128(0),
                 refund: U128(0),
                 refunded: U128(0),
+                referee_bonus: U128(0),
+                window_amounts: vec![],
             }
         }
     }
 
+    pub fn get_staking_snapshot_amount(&self, sale_id: u64, account_id: AccountId) -> Option<U128> {
+        let sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        sale.staking_snapshot
+            .and_then(|staking_snapshot| staking_snapshot.get(&account_id))
+            .map(U128)
+    }
+
+    pub fn get_lottery_winners(&self, sale_id: u64, from_index: u64, limit: u64) -> Vec<AccountId> {
+        let sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        if let Some(lottery_winners) = sale.lottery_winners {
+            let winners = lottery_winners.as_vector();
+            (from_index..std::cmp::min(from_index + limit, winners.len()))
+                .map(|index| winners.get(index).unwrap())
+                .collect()
+        } else {
+            vec![]
+        }
+    }
+
+    pub fn is_lottery_winner(&self, sale_id: u64, account_id: AccountId) -> bool {
+        let sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        sale.lottery_winners
+            .map(|lottery_winners| lottery_winners.contains(&account_id))
+            .unwrap_or(false)
+    }
+
+    /// Accounts with failed distribution that still have the purchase to claim.
+    pub fn get_sale_distribution_failures(
+        &self,
+        sale_id: u64,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<(AccountId, U128)> {
+        let sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        let failures = sale.distribution_failures.as_vector();
+        (from_index..std::cmp::min(from_index + limit, failures.len()))
+            .filter_map(|index| {
+                let account_id = failures.get(index).unwrap();
+                let account_sale: SaleAccount = sale.account_sales.get(&account_id)?.into();
+                if account_sale.claimed.0 == 0 {
+                    Some((account_id, account_sale.amount_to_claim))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn get_deposit_attributions(
+        &self,
+        sale_id: u64,
+        account_id: AccountId,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<DepositAttribution> {
+        let sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        if let Some(deposit_attributions) = sale.deposit_attributions.get(&account_id) {
+            (from_index..std::cmp::min(from_index + limit, deposit_attributions.len()))
+                .map(|index| deposit_attributions.get(index).unwrap())
+                .collect()
+        } else {
+            vec![]
+        }
+    }
+
+    /// Depositors of the sale with the number of their deposits in get_deposit_attributions.
+    pub fn get_sale_deposit_attributions(
+        &self,
+        sale_id: u64,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<(AccountId, u64)> {
+        let sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        let keys = sale.deposit_attributions.keys_as_vector();
+        let values = sale.deposit_attributions.values_as_vector();
+        (from_index..std::cmp::min(from_index + limit, keys.len()))
+            .map(|index| (keys.get(index).unwrap(), values.get(index).unwrap().len()))
+            .collect()
+    }
+
+    /// Logged payout failures of the sale with their index for retry_payout_failure.
+    pub fn get_sale_payout_failures(
+        &self,
+        sale_id: u64,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<(u64, PayoutFailure)> {
+        let sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        (from_index..std::cmp::min(from_index + limit, sale.payout_failures.len()))
+            .map(|index| (index, sale.payout_failures.get(index).unwrap()))
+            .collect()
+    }
+
     pub fn get_affiliate_account(
         &self,
         sale_id: u64,
         account_id: AccountId,
     ) -> AffiliateRewardAccount {
-        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
         if let Some(sale_account) = sale.account_affiliate_rewards.get(&account_id) {
             sale_account.into()
         } else {
@@ -925,7 +2947,6 @@ impl Contract {
 
     pub fn on_get_account_staked_balance(
         &mut self,
-        #[callback] staked_amount: U128,
         sale_id: u64,
         token_id: AccountId,
         sender_id: AccountId,
@@ -936,6 +2957,26 @@ impl Contract {
             env::current_account_id(),
             "ERR_NOT_OWNER"
         );
+        // Parsed by hand, so a malformed response returns the deposit instead of panicking.
+        let reason = match env::promise_result(0) {
+            PromiseResult::Successful(value) => match serde_json::from_slice::<U128>(&value) {
+                Ok(staked_amount) => Ok(staked_amount),
+                Err(_) => Err("malformed staked balance"),
+            },
+            _ => Err("staking contract call failed"),
+        };
+        let staked_amount = match reason {
+            Ok(staked_amount) => staked_amount,
+            Err(reason) => {
+                log!(
+                    "Deposit of {} returned to {}: {}",
+                    deposit_amount.0,
+                    sender_id,
+                    reason
+                );
+                return PromiseOrValue::Value(deposit_amount);
+            }
+        };
         log!("{} stake: {}", sender_id, staked_amount.0);
         PromiseOrValue::Value(U128(self.internal_sale_deposit(
             sale_id,
@@ -946,6 +2987,48 @@ impl Contract {
         )))
     }
 
+    pub fn on_get_account_staked_balances(
+        &mut self,
+        sale_id: u64,
+        token_id: AccountId,
+        sender_id: AccountId,
+        deposit_amount: U128,
+    ) -> PromiseOrValue<U128> {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "ERR_NOT_OWNER"
+        );
+        let mut staked_amount: Balance = 0;
+        for index in 0..env::promise_results_count() {
+            let reason = match env::promise_result(index) {
+                PromiseResult::Successful(value) => {
+                    if let Ok(amount) = serde_json::from_slice::<U128>(&value) {
+                        staked_amount += amount.0;
+                        continue;
+                    }
+                    "malformed staked balance"
+                }
+                _ => "staking contract call failed",
+            };
+            log!(
+                "Deposit of {} returned to {}: {}",
+                deposit_amount.0,
+                sender_id,
+                reason
+            );
+            return PromiseOrValue::Value(deposit_amount);
+        }
+        log!("{} total stake: {}", sender_id, staked_amount);
+        PromiseOrValue::Value(U128(self.internal_sale_deposit(
+            sale_id,
+            &token_id,
+            &sender_id,
+            staked_amount,
+            deposit_amount.0,
+        )))
+    }
+
     #[private]
     pub fn after_ft_on_transfer_near_deposit(
         &mut self,
@@ -953,8 +3036,9 @@ impl Contract {
         sender_id: AccountId,
         deposit_amount: U128,
     ) -> PromiseOrValue<U128> {
+        // Deposit wasn't recorded if the chain failed, so the whole amount goes back.
         self.internal_finalize_near_deposit(
-            return_amount.map(|v| v.0).unwrap_or(0),
+            return_amount.map(|v| v.0).unwrap_or(deposit_amount.0),
             sender_id,
             deposit_amount.0,
         )
@@ -966,19 +3050,110 @@ impl Contract {
         amount_to_claim: Balance,
         claim_token_account_id: AccountId,
         sale_id: u64,
+        storage_deposit_amount: Balance,
     ) -> Promise {
-        ext_fungible_token::ft_transfer(
+        if storage_deposit_amount > 0 {
+            // Only the registration cost is sent to the token, the rest is returned to the account.
+            ext_storage_management::storage_balance_of(
+                recipient_account_id.clone(),
+                claim_token_account_id.clone(),
+                NO_DEPOSIT,
+                GAS_FOR_STORAGE_VIEW,
+            )
+            .and(ext_storage_management::storage_balance_bounds(
+                claim_token_account_id,
+                NO_DEPOSIT,
+                GAS_FOR_STORAGE_VIEW,
+            ))
+            .then(ext_self::after_storage_balance_check(
+                recipient_account_id,
+                amount_to_claim.into(),
+                sale_id,
+                storage_deposit_amount.into(),
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_AFTER_STORAGE_BALANCE_CHECK,
+            ))
+        } else {
+            self.internal_transfer_purchase(
+                recipient_account_id,
+                amount_to_claim,
+                claim_token_account_id,
+                sale_id,
+                0,
+            )
+        }
+    }
+
+    #[private]
+    pub fn after_storage_balance_check(
+        &mut self,
+        #[callback_result] storage_balance: Result<Option<StorageBalance>, PromiseError>,
+        #[callback_result] storage_balance_bounds: Result<StorageBalanceBounds, PromiseError>,
+        account_id: AccountId,
+        amount_to_claim: U128,
+        sale_id: u64,
+        storage_deposit_amount: U128,
+    ) -> Promise {
+        let sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        let distribute_token_id = sale.distribute_token_id.expect("ERR_NO_TOKEN_ID");
+        let registration_cost = match (storage_balance, storage_balance_bounds) {
+            (Ok(None), Ok(storage_balance_bounds))
+                if storage_balance_bounds.min.0 <= storage_deposit_amount.0 =>
+            {
+                storage_balance_bounds.min.0
+            }
+            _ => 0,
+        };
+        let refund = storage_deposit_amount.0 - registration_cost;
+        if refund > 0 {
+            Promise::new(account_id.clone()).transfer(refund);
+        }
+        self.internal_transfer_purchase(
+            account_id,
+            amount_to_claim.0,
+            distribute_token_id,
+            sale_id,
+            registration_cost,
+        )
+    }
+
+    fn internal_transfer_purchase(
+        &mut self,
+        recipient_account_id: AccountId,
+        amount_to_claim: Balance,
+        claim_token_account_id: AccountId,
+        sale_id: u64,
+        registration_cost: Balance,
+    ) -> Promise {
+        let transfer = ext_fungible_token::ft_transfer(
             recipient_account_id.clone(),
             amount_to_claim.into(),
             Some(format!(
                 "Claim {} of {}. Sale #{}",
                 amount_to_claim, claim_token_account_id, sale_id
             )),
-            claim_token_account_id,
+            claim_token_account_id.clone(),
             ONE_YOCTO,
             GAS_FOR_FT_TRANSFER,
-        )
-        .then(ext_self::after_withdraw_purchase(
+        );
+        let transfer = if registration_cost > 0 {
+            ext_storage_management::storage_deposit(
+                Some(recipient_account_id.clone()),
+                Some(true),
+                claim_token_account_id,
+                registration_cost,
+                GAS_FOR_STORAGE_DEPOSIT,
+            )
+            .then(transfer)
+        } else {
+            transfer
+        };
+        transfer.then(ext_self::after_withdraw_purchase(
             recipient_account_id,
             amount_to_claim.into(),
             sale_id,
@@ -996,25 +3171,144 @@ impl Contract {
         sale_id: u64,
     ) -> bool {
         let promise_success = is_promise_success();
+        self.internal_complete_payout(
+            sale_id,
+            &account_id,
+            PayoutKind::Purchase,
+            amount_to_claim.0,
+            promise_success,
+        );
         if !promise_success {
-            let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+            self.internal_revert_purchase_claim(
+                &account_id,
+                amount_to_claim.0,
+                sale_id,
+                "Claim transfer failed",
+            );
+        }
+        promise_success
+    }
 
-            if let Some(v_sale_account) = sale.account_sales.get(&account_id) {
-                let mut account_sale: SaleAccount = v_sale_account.into();
-                account_sale.claimed = U128::from(account_sale.claimed.0 - amount_to_claim.0);
-                sale.account_sales
-                    .insert(&account_id, &VSaleAccount::Current(account_sale));
-                self.sales.insert(&sale_id, &VSale::Current(sale));
-                log!(
-                    "Purchase withdraw for {} failed. Tokens to recharge: {}",
-                    account_id,
-                    amount_to_claim.0
+    #[private]
+    pub fn after_distribute_purchase(
+        &mut self,
+        account_id: AccountId,
+        amount_to_claim: U128,
+        sale_id: u64,
+    ) -> bool {
+        let promise_success = is_promise_success();
+        self.internal_complete_payout(
+            sale_id,
+            &account_id,
+            PayoutKind::Purchase,
+            amount_to_claim.0,
+            promise_success,
+        );
+        if !promise_success {
+            // E.g. receiver is not registered with the token, it can claim the purchase later.
+            self.internal_revert_purchase_claim(
+                &account_id,
+                amount_to_claim.0,
+                sale_id,
+                "Distribution transfer failed",
+            );
+            let mut sale: Sale = self
+                .sales
+                .get(&sale_id)
+                .expect("ERR_NO_SALE")
+                .into_current(sale_id);
+            sale.distribution_failures.insert(&account_id);
+            self.sales.insert(&sale_id, &VSale::Current(sale));
+        }
+        promise_success
+    }
+
+    #[private]
+    pub fn after_withdraw_purchases(
+        &mut self,
+        account_id: AccountId,
+        sale_amounts: Vec<(u64, U128)>,
+    ) -> bool {
+        let promise_success = is_promise_success();
+        for (sale_id, amount_to_claim) in sale_amounts.iter() {
+            self.internal_complete_payout(
+                *sale_id,
+                &account_id,
+                PayoutKind::Purchase,
+                amount_to_claim.0,
+                promise_success,
+            );
+        }
+        if !promise_success {
+            for (sale_id, amount_to_claim) in sale_amounts {
+                self.internal_revert_purchase_claim(
+                    &account_id,
+                    amount_to_claim.0,
+                    sale_id,
+                    "Batch claim transfer failed",
                 );
             }
         }
         promise_success
     }
 
+    /// Releases the payout lock and counts the payout in the sale stats if it succeeded.
+    fn internal_complete_payout(
+        &mut self,
+        sale_id: u64,
+        account_id: &AccountId,
+        kind: PayoutKind,
+        amount: Balance,
+        promise_success: bool,
+    ) {
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+        sale.payouts_in_flight.remove(&(account_id.clone(), kind));
+        if promise_success {
+            match kind {
+                PayoutKind::Purchase => {
+                    sale.stats.claimed_amount += amount;
+                    sale.stats.num_claimers += 1;
+                }
+                PayoutKind::Refund => sale.stats.refunded_amount += amount,
+                PayoutKind::AffiliateReward => sale.stats.affiliate_rewards_claimed += amount,
+            }
+            self.sales.insert(&sale_id, &VSale::Current(sale));
+        }
+    }
+
+    fn internal_revert_purchase_claim(
+        &mut self,
+        account_id: &AccountId,
+        amount_to_claim: Balance,
+        sale_id: u64,
+        reason: &str,
+    ) {
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+
+        if let Some(v_sale_account) = sale.account_sales.get(account_id) {
+            let mut account_sale: SaleAccount = v_sale_account.into();
+            account_sale.claimed = U128::from(account_sale.claimed.0 - amount_to_claim);
+            sale.account_sales
+                .insert(account_id, &VSaleAccount::Current(account_sale));
+            sale.record_payout_failure(account_id, PayoutKind::Purchase, amount_to_claim, reason);
+            self.sales.insert(&sale_id, &VSale::Current(sale));
+            log!(
+                "Purchase withdraw for {} failed. Sale #{}. Tokens to recharge: {}",
+                account_id,
+                sale_id,
+                amount_to_claim
+            );
+        }
+    }
+
     pub(crate) fn refund_purchase(
         &mut self,
         recipient_account_id: AccountId,
@@ -1051,14 +3345,31 @@ impl Contract {
         sale_id: u64,
     ) -> bool {
         let promise_success = is_promise_success();
+        self.internal_complete_payout(
+            sale_id,
+            &account_id,
+            PayoutKind::Refund,
+            amount_to_refund.0,
+            promise_success,
+        );
         if !promise_success {
-            let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+            let mut sale: Sale = self
+                .sales
+                .get(&sale_id)
+                .expect("ERR_NO_SALE")
+                .into_current(sale_id);
 
             if let Some(v_sale_account) = sale.account_sales.get(&account_id) {
                 let mut account_sale: SaleAccount = v_sale_account.into();
                 account_sale.refunded = U128::from(account_sale.refunded.0 - amount_to_refund.0);
                 sale.account_sales
                     .insert(&account_id, &VSaleAccount::Current(account_sale));
+                sale.record_payout_failure(
+                    &account_id,
+                    PayoutKind::Refund,
+                    amount_to_refund.0,
+                    "Refund transfer failed",
+                );
                 self.sales.insert(&sale_id, &VSale::Current(sale));
                 log!(
                     "Purchase refund for {} failed. Tokens to recharge: {}",
@@ -1107,27 +3418,157 @@ impl Contract {
         sale_id: u64,
     ) -> bool {
         let promise_success = is_promise_success();
+        self.internal_complete_payout(
+            sale_id,
+            &account_id,
+            PayoutKind::AffiliateReward,
+            amount.0,
+            promise_success,
+        );
         if !promise_success {
-            let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+            self.internal_revert_affiliate_reward_claim(
+                &account_id,
+                amount.0,
+                sale_id,
+                "Claim transfer failed",
+            );
+        }
+        promise_success
+    }
 
-            if let Some(v_sale_account) = sale.account_affiliate_rewards.get(&account_id) {
-                let mut account_affiliate_reward: AffiliateRewardAccount = v_sale_account.into();
-                account_affiliate_reward.claimed =
-                    U128::from(account_affiliate_reward.claimed.0 - amount.0);
-                sale.account_affiliate_rewards.insert(
+    /// Claims affiliate rewards of the given sales with one transfer per reward token.
+    pub fn claim_all_affiliate_rewards(&mut self, sale_ids: Vec<u64>) -> Promise {
+        let account_id = env::predecessor_account_id();
+
+        // Reward token, total amount and amounts per sale.
+        let mut token_rewards: TokenPayouts = vec![];
+        for sale_id in sale_ids {
+            let mut sale: Sale = self
+                .sales
+                .get(&sale_id)
+                .expect("ERR_NO_SALE")
+                .into_current(sale_id);
+            let mut account_affiliate_reward: AffiliateRewardAccount =
+                match sale.account_affiliate_rewards.get(&account_id) {
+                    Some(v_account_affiliate_reward) => v_account_affiliate_reward.into(),
+                    None => continue,
+                };
+            let amount = sale.get_claimable_affiliate_reward(&account_affiliate_reward);
+            if amount == 0 || sale.is_payout_in_flight(&account_id, PayoutKind::AffiliateReward) {
+                continue;
+            }
+            account_affiliate_reward.claimed = U128(amount);
+            sale.lock_payout(&account_id, PayoutKind::AffiliateReward);
+            let token_account_id = sale.get_affiliate_reward_token_id();
+            sale.account_affiliate_rewards.insert(
+                &account_id,
+                &VAffiliateRewardAccount::Current(account_affiliate_reward),
+            );
+            self.sales.insert(&sale_id, &VSale::Current(sale));
+
+            if let Some(token_reward) = token_rewards
+                .iter_mut()
+                .find(|(token_id, _, _)| token_id == &token_account_id)
+            {
+                token_reward.1 += amount;
+                token_reward.2.push((sale_id, U128(amount)));
+            } else {
+                token_rewards.push((token_account_id, amount, vec![(sale_id, U128(amount))]));
+            }
+        }
+        assert!(!token_rewards.is_empty(), "ERR_NOTHING_TO_CLAIM");
+
+        let mut transfers: Option<Promise> = None;
+        for (token_account_id, amount, sale_rewards) in token_rewards {
+            log!("Amount to claim: {} of {}", amount, token_account_id);
+            let gas_for_after_transfer =
+                Gas(GAS_FOR_AFTER_FT_TRANSFER.0 * sale_rewards.len() as u64);
+            let transfer = ext_fungible_token::ft_transfer(
+                account_id.clone(),
+                amount.into(),
+                Some(format!(
+                    "Claim affiliate rewards {} of {}",
+                    amount, token_account_id
+                )),
+                token_account_id,
+                ONE_YOCTO,
+                GAS_FOR_FT_TRANSFER,
+            )
+            .then(ext_self::after_withdraw_affiliate_rewards(
+                account_id.clone(),
+                sale_rewards,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                gas_for_after_transfer,
+            ));
+            transfers = Some(match transfers {
+                Some(promise) => promise.and(transfer),
+                None => transfer,
+            });
+        }
+        transfers.unwrap()
+    }
+
+    #[private]
+    pub fn after_withdraw_affiliate_rewards(
+        &mut self,
+        account_id: AccountId,
+        sale_rewards: Vec<(u64, U128)>,
+    ) -> bool {
+        let promise_success = is_promise_success();
+        for (sale_id, amount) in sale_rewards.iter() {
+            self.internal_complete_payout(
+                *sale_id,
+                &account_id,
+                PayoutKind::AffiliateReward,
+                amount.0,
+                promise_success,
+            );
+        }
+        if !promise_success {
+            for (sale_id, amount) in sale_rewards {
+                self.internal_revert_affiliate_reward_claim(
                     &account_id,
-                    &VAffiliateRewardAccount::Current(account_affiliate_reward),
-                );
-                self.sales.insert(&sale_id, &VSale::Current(sale));
-                log!(
-                    "Affiliate rewards withdraw for {} failed. Tokens to recharge: {}",
-                    account_id,
-                    amount.0
+                    amount.0,
+                    sale_id,
+                    "Batch claim transfer failed",
                 );
             }
         }
         promise_success
     }
+
+    fn internal_revert_affiliate_reward_claim(
+        &mut self,
+        account_id: &AccountId,
+        amount: Balance,
+        sale_id: u64,
+        reason: &str,
+    ) {
+        let mut sale: Sale = self
+            .sales
+            .get(&sale_id)
+            .expect("ERR_NO_SALE")
+            .into_current(sale_id);
+
+        if let Some(v_sale_account) = sale.account_affiliate_rewards.get(account_id) {
+            let mut account_affiliate_reward: AffiliateRewardAccount = v_sale_account.into();
+            account_affiliate_reward.claimed =
+                U128::from(account_affiliate_reward.claimed.0 - amount);
+            sale.account_affiliate_rewards.insert(
+                account_id,
+                &VAffiliateRewardAccount::Current(account_affiliate_reward),
+            );
+            sale.record_payout_failure(account_id, PayoutKind::AffiliateReward, amount, reason);
+            self.sales.insert(&sale_id, &VSale::Current(sale));
+            log!(
+                "Affiliate rewards withdraw for {} failed. Sale #{}. Tokens to recharge: {}",
+                account_id,
+                sale_id,
+                amount
+            );
+        }
+    }
 }
 
 fn is_promise_success() -> bool {
@@ -1142,20 +3583,108 @@ fn is_promise_success() -> bool {
     }
 }
 
-fn get_amount_to_claim(
+/// Pro-rata share of the tokens purchased by `target_amount`.
+/// Share is floored after multiplication, so the sum of all shares never exceeds the supply.
+pub(crate) fn get_amount_to_claim(
     client_sum_deposit: Balance,
     total_filled_amount: Balance,
     target_amount: Balance,
     price: Balance,
     distribute_token_decimals: u8,
 ) -> u128 {
-    (U256::from(u128::pow(10, distribute_token_decimals as u32)) * U256::from(client_sum_deposit)
-        / U256::from(total_filled_amount)
-        * U256::from(target_amount)
+    let supply = get_purchase_amount(target_amount, price, distribute_token_decimals);
+    (U256::from(client_sum_deposit) * U256::from(supply) / U256::from(total_filled_amount))
+        .as_u128()
+}
+
+fn get_random_number(seed: &[u8], index: u64) -> u64 {
+    let mut data = seed.to_vec();
+    data.extend_from_slice(&index.to_le_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&env::sha256(&data)[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn get_staking_snapshot_leaf(account_id: &AccountId, staked_amount: Balance) -> CryptoHash {
+    let mut data = account_id.as_str().as_bytes().to_vec();
+    data.extend_from_slice(&staked_amount.to_le_bytes());
+    let mut leaf = CryptoHash::default();
+    leaf.copy_from_slice(&env::sha256(&data));
+    leaf
+}
+
+fn verify_staking_proof(
+    root: CryptoHash,
+    account_id: &AccountId,
+    staked_amount: Balance,
+    proof: Vec<CryptoHash>,
+) -> bool {
+    let mut hash = get_staking_snapshot_leaf(account_id, staked_amount);
+    for proof_element in proof {
+        let (left, right) = if hash <= proof_element {
+            (hash, proof_element)
+        } else {
+            (proof_element, hash)
+        };
+        hash.copy_from_slice(&env::sha256(&[left, right].concat()));
+    }
+    hash == root
+}
+
+pub(crate) fn get_purchase_amount(
+    deposit_amount: Balance,
+    price: Balance,
+    distribute_token_decimals: u8,
+) -> Balance {
+    (U256::from(u128::pow(10, distribute_token_decimals as u32)) * U256::from(deposit_amount)
         / U256::from(price))
     .as_u128()
 }
 
+/// Price of the bonding curve at the given collected amount, None if it doesn't fit Balance.
+fn get_bonding_curve_price(
+    bonding_curve: &BondingCurve,
+    price: Balance,
+    collected_amount: Balance,
+) -> Option<Balance> {
+    let price = match bonding_curve {
+        BondingCurve::Linear { step, increment } => {
+            U256::from(price)
+                + U256::from(increment.0) * U256::from(collected_amount) / U256::from(step.0)
+        }
+        BondingCurve::Exponential { step, growth } => {
+            let multiplier = get_fixed_pow(
+                U256::from(BONDING_CURVE_PRECISION)
+                    * U256::from(BONDING_CURVE_GROWTH_DENOMINATOR + *growth as u128)
+                    / U256::from(BONDING_CURVE_GROWTH_DENOMINATOR),
+                collected_amount / step.0,
+            )?;
+            U256::from(price).checked_mul(multiplier)? / U256::from(BONDING_CURVE_PRECISION)
+        }
+    };
+    if price > U256::from(u128::MAX) {
+        None
+    } else {
+        Some(price.as_u128())
+    }
+}
+
+/// Raises fixed point number with BONDING_CURVE_PRECISION to the given power.
+/// Returns None on overflow.
+fn get_fixed_pow(mut base: U256, mut exponent: u128) -> Option<U256> {
+    let mut result = U256::from(BONDING_CURVE_PRECISION);
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result.checked_mul(base)? / U256::from(BONDING_CURVE_PRECISION);
+        }
+        exponent >>= 1;
+        if exponent > 0 {
+            base = base.checked_mul(base)? / U256::from(BONDING_CURVE_PRECISION);
+        }
+    }
+    Some(result)
+}
+
 fn internal_get_affiliates_vector(
     affiliates: &LookupMap<u8, UnorderedSet<AccountId>>,
     level: u8,
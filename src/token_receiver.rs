@@ -1,6 +1,6 @@
 use near_sdk::json_types::U128;
 use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::{env, ext_contract, serde_json};
+use near_sdk::{env, ext_contract, serde_json, CryptoHash};
 
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 
@@ -10,6 +10,8 @@ use crate::*;
 const GAS_GET_ACCOUNT_STAKED_BALANCE: Gas = Gas(25_000_000_000_000);
 const GAS_ON_GET_ACCOUNT_STAKED_BALANCE: Gas = Gas(25_000_000_000_000);
 const NO_DEPOSIT: Balance = 0;
+/// Max number of staking contracts that can be queried in a single deposit.
+pub(crate) const MAX_STAKING_CONTRACTS_PER_DEPOSIT: usize = 4;
 
 #[ext_contract(ext_staking_pool)]
 pub trait ExtStakingPool {
@@ -23,6 +25,16 @@ pub struct SaleDeposit {
     pub sale_id: u64,
     /// Optional argument to point to the contract where this user has staked if sale requires this.
     pub staking_contract: Option<AccountId>,
+    /// Optional list of staking contracts to sum staked balance over. Takes precedence over `staking_contract`.
+    /// Empty list means all staking contracts of the sale.
+    #[serde(default)]
+    pub staking_contracts: Option<Vec<AccountId>>,
+    /// Staked amount from the sale staking snapshot, required if snapshot is a merkle root.
+    #[serde(default)]
+    pub staked_amount: Option<U128>,
+    /// Merkle proof of the staked amount in the sale staking snapshot.
+    #[serde(default)]
+    pub staking_proof: Option<Vec<CryptoHash>>,
 }
 
 impl Contract {
@@ -42,7 +54,7 @@ impl Contract {
             .sales
             .get(&sale_deposit.sale_id)
             .expect("ERR_NO_SALE")
-            .into();
+            .into_current(sale_deposit.sale_id);
         assert_eq!(sale.deposit_token_id, token_id, "ERR_WRONG_TOKEN");
         if sale.hard_max_amount_limit {
             assert!(
@@ -57,8 +69,69 @@ impl Contract {
             "ERR_SALE_DONE"
         );
 
+        // Check staked amount against the snapshot if sale has one.
+        if sale.staking_snapshot.is_some() || sale.staking_snapshot_root.is_some() {
+            let staked_amount = sale.get_snapshot_staked_amount(
+                &sender_id,
+                sale_deposit.staked_amount,
+                sale_deposit.staking_proof,
+            );
+            return PromiseOrValue::Value(U128(self.internal_sale_deposit(
+                sale_deposit.sale_id,
+                &token_id,
+                &sender_id,
+                staked_amount,
+                amount.0,
+            )));
+        }
+
         // Send call to check how much is staked if staking is required.
         if sale.staking_contracts.len() > 0 {
+            if let Some(staking_contracts) = sale_deposit.staking_contracts {
+                let staking_contracts = if staking_contracts.is_empty() {
+                    sale.staking_contracts.clone()
+                } else {
+                    staking_contracts
+                };
+                assert!(
+                    staking_contracts.len() <= MAX_STAKING_CONTRACTS_PER_DEPOSIT,
+                    "ERR_TOO_MANY_STAKING_CONTRACTS"
+                );
+                let mut staked_balances: Option<Promise> = None;
+                for (index, staking_contract) in staking_contracts.iter().enumerate() {
+                    assert!(
+                        sale.staking_contracts.contains(staking_contract),
+                        "ERR_NOT_WHITELISTED_STAKING_CONTRACT"
+                    );
+                    assert!(
+                        !staking_contracts[..index].contains(staking_contract),
+                        "ERR_DUPLICATE_STAKING_CONTRACT"
+                    );
+                    let staked_balance = ext_staking_pool::get_account_staked_balance(
+                        sender_id.clone(),
+                        staking_contract.clone(),
+                        NO_DEPOSIT,
+                        GAS_GET_ACCOUNT_STAKED_BALANCE,
+                    );
+                    staked_balances = Some(match staked_balances {
+                        Some(promise) => promise.and(staked_balance),
+                        None => staked_balance,
+                    });
+                }
+                return PromiseOrValue::Promise(
+                    staked_balances
+                        .expect("ERR_MUST_HAVE_STAKING_CONTRACT")
+                        .then(ext_self::on_get_account_staked_balances(
+                            sale_deposit.sale_id,
+                            token_id,
+                            sender_id,
+                            amount,
+                            env::current_account_id(),
+                            NO_DEPOSIT,
+                            GAS_ON_GET_ACCOUNT_STAKED_BALANCE,
+                        )),
+                );
+            }
             let staking_contract = sale_deposit
                 .staking_contract
                 .expect("ERR_MUST_HAVE_STAKING_CONTRACT");